@@ -8,20 +8,34 @@
 
 use clap::{Arg, ArgAction, Command};
 use hisho_core::build_tool;
-use hisho_core::config_models::Project;
+use hisho_core::check;
+use hisho_core::check::Severity;
+use hisho_core::config_models::{split_reference, ExportFormat, Process, Project};
+use hisho_core::environment;
+use hisho_core::environment::EnvironmentOverrides;
+use hisho_core::export;
 use hisho_core::files;
 use hisho_core::git;
+use hisho_core::hook;
+use hisho_core::hook::HookShell;
 use hisho_core::log;
+use hisho_core::migrate;
+use hisho_core::project;
+use hisho_core::project::{ProjectFormat, ProjectLoader};
+use hisho_core::schema;
+use hisho_core::selection;
+use hisho_core::shell;
+use hisho_core::state;
 use hisho_core::template::TemplateVariables;
+use hisho_core::trust;
 use hisho_core::{arg_parse, command};
-use ron::error::SpannedResult;
+use std::collections::HashMap;
 use std::process::exit;
-use std::{env, fs, io};
+use std::{env, io};
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let version = env!("CARGO_PKG_VERSION");
-    let default_project_file = "hisho.ron";
 
     let clap_command = Command::new("hisho")
         .about(
@@ -34,13 +48,33 @@ async fn main() -> io::Result<()> {
                 .short('f')
                 .long("file")
                 .value_name("FILE")
-                .default_value(default_project_file)
-                .help("Path to the Hisho project file"),
+                .help("Path to the Hisho project file, searched in parent directories if not set"),
         )
         .subcommand(
             Command::new("run")
                 .visible_aliases(["r", "cmd"])
                 .about("Run a command")
+                .arg(
+                    Arg::new("environment")
+                        .help("The environment to use instead of the one of the command")
+                        .short('e')
+                        .long("env")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("set")
+                        .help("Set a variable of the environment, with the highest precedence")
+                        .long("set")
+                        .value_name("KEY=VALUE")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("env-file")
+                        .help("Load the variables of a .env file on top of the environment, before '--set'")
+                        .long("env-file")
+                        .value_name("FILE")
+                        .action(ArgAction::Append),
+                )
                 .arg(
                     Arg::new("command")
                         .help("The command to run")
@@ -69,60 +103,356 @@ async fn main() -> io::Result<()> {
                         .action(ArgAction::Set),
                 )
                 .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("exec")
+                .about("Run a program within an environment, like 'hisho exec -e dev -- psql'")
+                .arg(
+                    Arg::new("environment")
+                        .help("The environment to use, like 'dev' or 'web:dev'")
+                        .short('e')
+                        .long("env")
+                        .default_value("")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("program")
+                        .help("The program to run and its arguments")
+                        .action(ArgAction::Set)
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true)
+                        .num_args(1..)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("shell")
+                .about("Open an interactive shell within an environment")
+                .arg(
+                    Arg::new("environment")
+                        .help("The environment to use, like 'dev' or 'web:dev'")
+                        .short('e')
+                        .long("env")
+                        .default_value("")
+                        .action(ArgAction::Set),
+                ),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Convert the project file into another format")
+                .arg(
+                    Arg::new("output")
+                        .help("The file to write, the format is chosen by the extension (ron, toml, yaml, json)")
+                        .action(ArgAction::Set)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Validate the project configuration, exits non-zero on errors"),
+        )
+        .subcommand(
+            Command::new("schema").about("Print the JSON Schema of the project configuration"),
+        )
+        .subcommand(
+            Command::new("hook")
+                .about("Print a snippet that loads the environment when changing into a project directory")
+                .arg(
+                    Arg::new("shell")
+                        .help("The shell to print the snippet for, evaluate it in the startup file of the shell")
+                        .value_parser(HookShell::ALL.map(|shell| shell.name()))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("allow")
+                .about("Allow the shell hook to load the project, until the project file, its includes or hisho.local.ron change"),
+        )
+        .subcommand(
+            Command::new("deny").about("Stop the shell hook from loading the project"),
+        )
+        .subcommand(
+            Command::new("migrate")
+                .about("Upgrade the project file to the current version, in place")
+                .arg(
+                    Arg::new("dry-run")
+                        .help("Only print the changes, do not write the file")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("env")
+                .about("Inspect the environments of the project")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("show")
+                        .about("Print the variables of an environment and where they came from, secret values are masked")
+                        .arg(
+                            Arg::new("environment")
+                                .help("The environment to show, like 'dev' or 'web:dev'")
+                                .action(ArgAction::Set)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("format")
+                                .help("The format to print the variables in")
+                                .long("format")
+                                .value_parser(["table", "json"])
+                                .default_value("table"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("export")
+                        .about("Print the variables of an environment in a format for other tools, including secret values")
+                        .arg(
+                            Arg::new("environment")
+                                .help("The environment to export, like 'dev' or 'web:dev'")
+                                .action(ArgAction::Set)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("format")
+                                .help("The format to export the variables in")
+                                .long("format")
+                                .value_parser(ExportFormat::ALL.map(|format| format.name()))
+                                .default_value("dotenv"),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .help("Write the variables to this file instead of printing them")
+                                .short('o')
+                                .long("output")
+                                .action(ArgAction::Set),
+                        ),
+                )
+                .subcommand(
+                    Command::new("use")
+                        .about("Remember the environment for commands and programs that do not choose one, or print it")
+                        .arg(
+                            Arg::new("environment")
+                                .help("The environment to use by default, like 'dev' or 'web:dev'")
+                                .action(ArgAction::Set),
+                        )
+                        .arg(
+                            Arg::new("clear")
+                                .help("Forget the default environment")
+                                .long("clear")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("environment"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("which")
+                        .about("Print which environment a command would use and which environment rules match")
+                        .arg(
+                            Arg::new("command")
+                                .help("The command to check, like 'test' or 'web:test'")
+                                .action(ArgAction::Set),
+                        )
+                        .arg(
+                            Arg::new("environment")
+                                .help("The environment requested on the command line, like with 'run -e'")
+                                .short('e')
+                                .long("env")
+                                .action(ArgAction::Set),
+                        ),
+                )
+                .subcommand(
+                    Command::new("diff-export")
+                        .about("Print the statements to update the shell to the environment of the current directory, used by the shell hook")
+                        .arg(
+                            Arg::new("shell")
+                                .help("The shell to print the statements for")
+                                .long("shell")
+                                .value_parser(HookShell::ALL.map(|shell| shell.name()))
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("environment")
+                                .help("The environment to load, defaults to $HISHO_ENV, the environment chosen by the project or 'dev', an empty value unloads the environment")
+                                .short('e')
+                                .long("env")
+                                .action(ArgAction::Set),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Inspect the project configuration")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("show")
+                        .about("Print the project configuration")
+                        .arg(
+                            Arg::new("merged")
+                                .help("Merge the override files and show where each entry came from")
+                                .long("merged")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("format")
+                                .help("The format to print the configuration in")
+                                .long("format")
+                                .value_parser(["ron", "toml", "yaml", "json"])
+                                .default_value("ron"),
+                        ),
+                ),
         );
     let matches = clap_command.clone().get_matches();
 
-    log::print(format!(
-        "Hisho v{} (hisho_cli2) by Thomas Obernosterer",
-        version
-    ));
+    // the schema does not need a project, and is printed without banner so it can be redirected
+    if let Some(("schema", _)) = matches.subcommand() {
+        println!("{}", schema::project_schema_json());
+        return Ok(());
+    }
 
-    let project_file_path = matches.get_one::<String>("project-file").unwrap();
+    // the hook runs before every prompt, so it must not load the project unless something changed
+    if let Some(("hook", hook_matches)) = matches.subcommand() {
+        let shell = hook_shell(hook_matches);
+        let executable = env::current_exe()
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| "hisho".to_string());
+        print!("{}", hook::hook_snippet(shell, &executable));
+        return Ok(());
+    }
+    if let Some(("env", env_matches)) = matches.subcommand() {
+        if let Some(("diff-export", diff_matches)) = env_matches.subcommand() {
+            let shell = hook_shell(diff_matches);
+            let environment = diff_matches
+                .get_one::<String>("environment")
+                .cloned()
+                .or_else(|| env::var(hook::ENVIRONMENT_VARIABLE).ok());
+            let previous = env::var(hook::DIFF_VARIABLE).ok();
+            let dir = env::current_dir().unwrap_or_default();
+            print!(
+                "{}",
+                hook::diff_export(shell, &dir, environment.as_deref(), previous.as_deref())
+            );
+            return Ok(());
+        }
+    }
 
-    let project_file = files::resolve_path(project_file_path.clone()).unwrap_or_else(|e| {
-        log::error(format!(
-            "Could not find project file '{}': {:?}",
-            project_file_path,
-            e.to_string()
+    // the output of env and config is meant to be used by other tools, so it is printed without banner
+    if !matches!(matches.subcommand(), Some(("env" | "config", _))) {
+        log::print(format!(
+            "Hisho v{} (hisho_cli2) by Thomas Obernosterer",
+            version
         ));
+    }
+
+    let invocation_dir = env::current_dir().unwrap_or_else(|e| {
+        log::error(format!("Could not resolve current directory: {}", e));
         exit(2);
     });
-    let workdir = project_file.parent().unwrap_or_else(|| {
-        log::error(format!(
-            "Could not resolve parent directory of project file '{}'",
-            project_file_path,
-        ));
+    let loader = match matches.get_one::<String>("project-file") {
+        Some(project_file_path) => Ok(ProjectLoader::new(project_file_path)),
+        None => ProjectLoader::discover(invocation_dir.as_path()),
+    }
+    .unwrap_or_else(|e| {
+        log::error(e.to_string());
         exit(2);
     });
 
-    let data_from_file = fs::read_to_string(project_file_path.clone());
-    if let Err(e) = data_from_file {
-        log::error(format!(
-            "Could not read project file '{}': {:?}",
-            project_file_path,
-            e.to_string()
-        ));
+    // allowing does not load the project, its environments must not be resolved before
+    if let Some((name @ ("allow" | "deny"), _)) = matches.subcommand() {
+        let project_file = files::string_to_path(loader.path());
+        let result = if name == "allow" {
+            trust::allow(&project_file)
+        } else {
+            trust::deny(&project_file)
+        };
+        if let Err(e) = result {
+            log::error(e);
+            exit(2);
+        }
+        if name == "allow" {
+            log::print(format!(
+                "Allowed the shell hook to load project file '{}'",
+                loader.path()
+            ));
+        } else {
+            log::print(format!(
+                "Denied the shell hook to load project file '{}'",
+                loader.path()
+            ));
+        }
+        return Ok(());
+    }
+
+    // migrate before loading, the project may only load after it was migrated
+    if let Some(("migrate", migrate_matches)) = matches.subcommand() {
+        let dry_run = migrate_matches.get_flag("dry-run");
+        let migrated =
+            migrate::migrate_project_file(files::string_to_path(loader.path()).as_path(), dry_run)
+                .unwrap_or_else(|e| {
+                    log::error(e.to_string());
+                    exit(2);
+                });
+        if migrated.from == migrated.to {
+            log::print(format!(
+                "Project file '{}' is already at version {}",
+                loader.path(),
+                migrated.to
+            ));
+            return Ok(());
+        }
+        for change in &migrated.changes {
+            log::print(format!("\t{}", change));
+        }
+        if dry_run {
+            println!("{}", migrated.data);
+        } else {
+            log::print(format!(
+                "Migrated project file '{}' from version {} to {}",
+                loader.path(),
+                migrated.from,
+                migrated.to
+            ));
+        }
+        if !migrated.comments_kept {
+            log::warn(
+                "The project file was serialized again, comments and formatting were not kept"
+                    .to_string(),
+            );
+        }
+        return Ok(());
+    }
+
+    let project: Project = loader.load().unwrap_or_else(|e| {
+        log::error(e.to_string());
         exit(2);
+    });
+    let workdir = files::string_to_path(&project.workdir);
+    for outdated in std::iter::once(&project).chain(project.member_projects.iter()) {
+        if outdated.version < migrate::CURRENT_VERSION {
+            log::warn(format!(
+                "Project '{}' uses version {} of the project file, the current version is {}. Run 'hisho migrate' to upgrade it",
+                outdated.name,
+                outdated.version,
+                migrate::CURRENT_VERSION
+            ));
+        } else if outdated.version > migrate::CURRENT_VERSION {
+            log::warn(format!(
+                "Project '{}' uses version {} of the project file, this version of Hisho only supports up to version {}",
+                outdated.name,
+                outdated.version,
+                migrate::CURRENT_VERSION
+            ));
+        }
     }
-    let project_data: SpannedResult<Project> = ron::from_str(data_from_file.unwrap().as_str());
-    if let Err(e) = project_data {
+
+    // relative paths of the project are relative to the directory of the project file
+    if let Err(e) = env::set_current_dir(&workdir) {
         log::error(format!(
-            "Could not parse project file '{}': {:?}",
-            project_file_path,
-            e.to_string()
+            "Could not change into project directory '{}': {}",
+            project.workdir, e
         ));
         exit(2);
     }
-    let mut project_mut = project_data.unwrap();
-    project_mut.workdir = workdir
-        .to_path_buf()
-        .into_os_string()
-        .into_string()
-        .unwrap();
-    let project: Project = project_mut;
 
     let mut vars = TemplateVariables::new();
-    vars.insert("git", git::fetch_repo_vars(workdir));
+    vars.insert("git", git::fetch_repo_vars(workdir.as_path()));
 
     match matches.subcommand() {
         Some(("run", run_matches)) => {
@@ -135,18 +465,60 @@ async fn main() -> io::Result<()> {
 
             // take the first argument as command name
             let command_name = args.first().unwrap();
+            let (target_project, target_name) = resolve_reference(&project, command_name);
+            if !std::ptr::eq(target_project, &project) {
+                let target_workdir = files::string_to_path(&target_project.workdir);
+                vars.insert("git", git::fetch_repo_vars(target_workdir.as_path()));
+            }
 
             // parse options from the arguments for arg template variables
             let command_options = arg_parse::parse(args.clone());
+            let overrides = EnvironmentOverrides {
+                environment: run_matches.get_one::<String>("environment").cloned(),
+                // the files are relative to where hisho was invoked, not to the project
+                env_files: run_matches
+                    .get_many::<String>("env-file")
+                    .unwrap_or_default()
+                    .map(|path| {
+                        let path = invocation_dir.join(path);
+                        if !path.is_file() {
+                            log::error(format!("Could not find the env file '{}'", path.display()));
+                            exit(2);
+                        }
+                        path
+                    })
+                    .collect(),
+                values: run_matches
+                    .get_many::<String>("set")
+                    .unwrap_or_default()
+                    .map(|entry| match entry.split_once('=') {
+                        Some((key, value)) if !key.is_empty() => {
+                            (key.to_string(), value.to_string())
+                        }
+                        _ => {
+                            log::error(format!("Expected KEY=VALUE for '--set', got '{}'", entry));
+                            exit(2);
+                        }
+                    })
+                    .collect(),
+            };
 
             let mut command_found = false;
-            for cmd in &project.commands {
-                if cmd.name == *command_name {
+            for cmd in &target_project.commands {
+                if cmd.name == target_name {
                     command_found = true;
 
                     vars.insert("arg", command_options.clone());
 
-                    command::run_command(&project, cmd, &vars, &args).await;
+                    command::run_workspace_command(
+                        &project,
+                        target_project,
+                        cmd,
+                        &vars,
+                        &args,
+                        &overrides,
+                    )
+                    .await;
                     break;
                 }
             }
@@ -159,12 +531,27 @@ async fn main() -> io::Result<()> {
             let build_name = build_matches.get_one::<String>("build_step").unwrap();
             let environment = build_matches.get_one::<String>("environment").unwrap();
 
+            let (target_project, target_name) = resolve_reference(&project, build_name);
+            if !std::ptr::eq(target_project, &project) {
+                let target_workdir = files::string_to_path(&target_project.workdir);
+                vars.insert("git", git::fetch_repo_vars(target_workdir.as_path()));
+            }
+
+            let environment = or_default_environment(target_project, environment);
+
             let mut build_found = false;
-            for step in &project.build {
-                if step.name == *build_name {
+            for step in &target_project.build {
+                if step.name == target_name {
                     build_found = true;
 
-                    build_tool::run_build(&project, step, environment.as_str(), &vars).await;
+                    build_tool::run_workspace_build(
+                        &project,
+                        target_project,
+                        step,
+                        environment.as_str(),
+                        &vars,
+                    )
+                    .await;
                     break;
                 }
             }
@@ -173,6 +560,281 @@ async fn main() -> io::Result<()> {
                 exit(2);
             }
         }
+        Some(("exec", exec_matches)) => {
+            let environment_name = exec_matches.get_one::<String>("environment").unwrap();
+            let mut program = exec_matches
+                .get_many::<String>("program")
+                .unwrap()
+                .map(|s| s.to_string());
+
+            let (target_project, target_name) = resolve_reference(&project, environment_name);
+            let target_name = or_default_environment(target_project, target_name);
+            // the program runs where hisho was invoked, not in the project
+            let process = Process {
+                command: program.next().unwrap(),
+                args: program.collect(),
+                cwd: invocation_dir.to_string_lossy().to_string(),
+            };
+            let status = command::run_in_environment(
+                target_project,
+                &target_name,
+                &process,
+                HashMap::new(),
+                &vars,
+            )
+            .await;
+            exit(status.and_then(|status| status.code()).unwrap_or(2));
+        }
+        Some(("shell", shell_matches)) => {
+            let environment_name = shell_matches.get_one::<String>("environment").unwrap();
+
+            let (target_project, target_name) = resolve_reference(&project, environment_name);
+            let target_name = or_default_environment(target_project, target_name);
+            let marker = if target_name.is_empty() {
+                format!("(hisho:{})", target_project.name)
+            } else {
+                format!("(hisho:{}/{})", target_project.name, target_name)
+            };
+            let mut interactive = shell::interactive_shell(&marker).unwrap_or_else(|e| {
+                log::error(format!("Could not prepare the shell: {}", e));
+                exit(2);
+            });
+            interactive.process.cwd = invocation_dir.to_string_lossy().to_string();
+            let mut extra_env = std::mem::take(&mut interactive.env);
+            extra_env.insert("HISHO_PROJECT".to_string(), target_project.name.clone());
+            extra_env.insert("HISHO_ENVIRONMENT".to_string(), target_name.to_string());
+
+            log::print(format!(
+                "Opening '{}' within {}, exit the shell to leave",
+                interactive.process.command, marker
+            ));
+            let status = command::run_in_environment(
+                target_project,
+                &target_name,
+                &interactive.process,
+                extra_env,
+                &vars,
+            )
+            .await;
+            // exit does not run destructors, remove the startup files first
+            drop(interactive);
+            exit(status.and_then(|status| status.code()).unwrap_or(2));
+        }
+        Some(("convert", convert_matches)) => {
+            let output = convert_matches.get_one::<String>("output").unwrap();
+
+            // both paths are relative to where hisho was invoked, not to the project
+            if let Err(e) = project::convert_project_file(
+                invocation_dir.join(loader.path()).as_path(),
+                invocation_dir.join(output).as_path(),
+            ) {
+                log::error(e.to_string());
+                exit(2);
+            }
+            log::print(format!("Converted project file to '{}'", output));
+        }
+        Some(("check", _)) => {
+            log::print(format!("Checking project '{}' ..", project.name));
+            let problems = check::check_project(&project);
+            let errors = problems
+                .iter()
+                .filter(|p| p.severity == Severity::Error)
+                .count();
+            for problem in &problems {
+                match problem.severity {
+                    Severity::Error => log::error(format!("\t{}", problem)),
+                    Severity::Warning => log::warn(format!("\t{}", problem)),
+                }
+            }
+            log::print(format!(
+                "Found {} error(s) and {} warning(s)",
+                errors,
+                problems.len() - errors
+            ));
+            if errors > 0 {
+                exit(2);
+            }
+        }
+        Some(("env", env_matches)) => {
+            if let Some(("show", show_matches)) = env_matches.subcommand() {
+                let environment_name = show_matches.get_one::<String>("environment").unwrap();
+                let format = show_matches.get_one::<String>("format").unwrap();
+
+                let (target_project, target_name) = resolve_reference(&project, environment_name);
+                let target_workdir = files::string_to_path(&target_project.workdir);
+                let (env, provenance) = environment::fetch_environment_with_provenance(
+                    target_name,
+                    &target_project.environments,
+                    target_workdir.as_path(),
+                )
+                .unwrap_or_else(|e| {
+                    log::error(e.to_string());
+                    exit(2);
+                });
+                if format == "json" {
+                    println!(
+                        "{}",
+                        environment::provenance_json(target_name, &env, &provenance)
+                    );
+                } else {
+                    println!("{}", environment::provenance_table(&env, &provenance));
+                }
+            }
+            if let Some(("use", use_matches)) = env_matches.subcommand() {
+                let clear = use_matches.get_flag("clear");
+                let (target_project, target_name) =
+                    match use_matches.get_one::<String>("environment") {
+                        Some(environment_name) => resolve_reference(&project, environment_name),
+                        None => (&project, ""),
+                    };
+                let target_workdir = files::string_to_path(&target_project.workdir);
+                if target_name.is_empty() && !clear {
+                    match state::default_environment(&target_workdir) {
+                        Some(environment) => println!("{}", environment),
+                        None => log::print(format!(
+                            "Project '{}' has no default environment",
+                            target_project.name
+                        )),
+                    }
+                    return Ok(());
+                }
+                if !clear
+                    && !target_project
+                        .environments
+                        .iter()
+                        .any(|e| e.name == target_name)
+                {
+                    log::error(format!("Could not find environment '{}'", target_name));
+                    exit(2);
+                }
+
+                let mut project_state = state::load_state(&target_workdir).unwrap_or_else(|e| {
+                    log::error(e);
+                    exit(2);
+                });
+                project_state.environment = Some(target_name.to_string()).filter(|_| !clear);
+                if let Err(e) = state::save_state(&target_workdir, &project_state) {
+                    log::error(e);
+                    exit(2);
+                }
+                if clear {
+                    log::print(format!(
+                        "Cleared the default environment of project '{}'",
+                        target_project.name
+                    ));
+                } else {
+                    log::print(format!(
+                        "Using environment '{}' by default in project '{}'",
+                        target_name, target_project.name
+                    ));
+                }
+            }
+            if let Some(("which", which_matches)) = env_matches.subcommand() {
+                let requested = which_matches.get_one::<String>("environment");
+                let (target_project, pinned) = match which_matches.get_one::<String>("command") {
+                    Some(command_name) => {
+                        let (target_project, target_name) =
+                            resolve_reference(&project, command_name);
+                        match target_project
+                            .commands
+                            .iter()
+                            .find(|cmd| cmd.name == target_name)
+                        {
+                            Some(cmd) => (target_project, cmd.environment.as_str()),
+                            None => {
+                                log::error(format!("Could not find command '{}'", command_name));
+                                exit(2);
+                            }
+                        }
+                    }
+                    None => (&project, ""),
+                };
+                let chosen = selection::select_environment(
+                    target_project,
+                    requested.map(String::as_str),
+                    pinned,
+                );
+                for line in selection::explain_selection(target_project, &chosen) {
+                    log::print(line);
+                }
+            }
+            if let Some(("export", export_matches)) = env_matches.subcommand() {
+                let environment_name = export_matches.get_one::<String>("environment").unwrap();
+                let format = export_matches.get_one::<String>("format").unwrap();
+                let format = ExportFormat::from_name(format).unwrap();
+
+                let (target_project, target_name) = resolve_reference(&project, environment_name);
+                let target_workdir = files::string_to_path(&target_project.workdir);
+                let env = environment::fetch_environment(
+                    target_name,
+                    &target_project.environments,
+                    target_workdir.as_path(),
+                )
+                .unwrap_or_else(|e| {
+                    log::error(e.to_string());
+                    exit(2);
+                });
+                let data = export::export_environment(&env, format).unwrap_or_else(|e| {
+                    log::error(e);
+                    exit(2);
+                });
+                match export_matches.get_one::<String>("output") {
+                    // the output is relative to where hisho was invoked, not to the project
+                    Some(output) => {
+                        if let Err(e) = std::fs::write(invocation_dir.join(output), data) {
+                            log::error(format!("Could not write '{}': {}", output, e));
+                            exit(2);
+                        }
+                    }
+                    None => print!("{}", data),
+                }
+            }
+        }
+        Some(("config", config_matches)) => {
+            if let Some(("show", show_matches)) = config_matches.subcommand() {
+                let merged = show_matches.get_flag("merged");
+                let format = show_matches.get_one::<String>("format").unwrap();
+                let format = ProjectFormat::from_name(format).unwrap();
+
+                let show_loader = ProjectLoader::new(
+                    invocation_dir
+                        .join(loader.path())
+                        .to_string_lossy()
+                        .as_ref(),
+                )
+                .with_overrides(merged);
+                let (shown_project, origins) =
+                    show_loader.load_with_origins().unwrap_or_else(|e| {
+                        log::error(e.to_string());
+                        exit(2);
+                    });
+                let text = project::serialize_project(&shown_project, format).unwrap_or_else(|e| {
+                    log::error(e.to_string());
+                    exit(2);
+                });
+                println!("{}", text);
+
+                if merged {
+                    log::print("Origins:".to_string());
+                    for ((kind, name), paths) in &origins {
+                        let mut paths = paths.iter().map(|p| p.display().to_string());
+                        let defined_in = paths.next().unwrap_or_default();
+                        let overridden_in = paths.collect::<Vec<String>>();
+                        if overridden_in.is_empty() {
+                            log::print(format!("\t{} '{}' from '{}'", kind, name, defined_in));
+                        } else {
+                            log::print(format!(
+                                "\t{} '{}' from '{}', overridden by '{}'",
+                                kind,
+                                name,
+                                defined_in,
+                                overridden_in.join("', '")
+                            ));
+                        }
+                    }
+                }
+            }
+        }
         _ => {
             let mut help_suffix = String::new();
             if !project.build.is_empty() {
@@ -199,8 +861,47 @@ async fn main() -> io::Result<()> {
                     project.name, known_commands
                 );
             }
+            for member in &project.member_projects {
+                if !member.commands.is_empty() {
+                    let known_commands = member
+                        .commands
+                        .iter()
+                        .map(|cmd| format!("  {}:{}", member.name, cmd.name))
+                        .collect::<Vec<String>>()
+                        .join("\n");
+                    help_suffix += &*format!(
+                        "Commands from Member Project '{}':\n{}\n\n",
+                        member.name, known_commands
+                    );
+                }
+            }
             clap_command.after_help(help_suffix).print_help()?;
         }
     }
     Ok(())
 }
+
+/// The environment name, or the environment chosen by the project if it is empty
+fn or_default_environment(project: &Project, environment: &str) -> String {
+    selection::select_environment(project, Some(environment), "").environment
+}
+
+/// The shell selected with the `shell` argument
+fn hook_shell(matches: &clap::ArgMatches) -> HookShell {
+    matches
+        .get_one::<String>("shell")
+        .and_then(|name| HookShell::from_name(name))
+        .unwrap_or(HookShell::Bash)
+}
+
+/// Resolve a reference like `api:test` to the member project and the name inside of it
+fn resolve_reference<'a>(project: &'a Project, reference: &'a str) -> (&'a Project, &'a str) {
+    let (project_name, name) = split_reference(reference);
+    match project.resolve_project(project_name) {
+        Some(target_project) => (target_project, name),
+        None => {
+            log::error(format!("Could not find project '{}'", project_name));
+            exit(2);
+        }
+    }
+}
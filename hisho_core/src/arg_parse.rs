@@ -24,7 +24,7 @@ pub fn parse(args: Vec<String>) -> HashMap<String, String> {
         let arg = &args[i];
 
         if arg.starts_with("--") {
-            let parts: Vec<&str> = arg.splitn(2, |c| c == '=').collect();
+            let parts: Vec<&str> = arg.splitn(2, '=').collect();
             let (key, value) = if parts.len() > 1 {
                 (parts[0][2..].to_string(), parts[1].to_string())
             } else if i + 1 < args.len() && !args[i + 1].starts_with('-') {
@@ -7,13 +7,17 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::collections::HashMap;
+use std::path::Path;
+use std::ptr;
 
-use crate::config_models::{BuildStep, BuildSteps, Command, Environment, Process, Project};
+use crate::config_models::{
+    split_reference, BuildStep, BuildSteps, Command, Isolation, Process, Project,
+};
 use crate::environment::fetch_environment;
 use crate::shell;
 use crate::template;
 use crate::template::TemplateVariables;
-use crate::{containers, files, log};
+use crate::{containers, export, files, git, log};
 
 /////// DEPRECATED SECTION BEGIN ///////
 
@@ -38,14 +42,36 @@ pub async fn run_build(
     step: &BuildStep,
     environment: &str,
     default_vars: &TemplateVariables,
+) -> bool {
+    run_workspace_build(project, project, step, environment, default_vars).await
+}
+
+/// Run a build step of a project that is part of a workspace
+///
+/// The `project` is either the `workspace` itself or one of its members,
+/// it provides the environment, the containers and the build step.
+/// References to build steps of other projects, like `web:build`, are resolved
+/// against the members of the `workspace`.
+pub async fn run_workspace_build(
+    workspace: &Project,
+    project: &Project,
+    step: &BuildStep,
+    environment: &str,
+    default_vars: &TemplateVariables,
 ) -> bool {
     let mut vars = default_vars.clone();
-    let env = fetch_environment(
+    let env = match fetch_environment(
         environment,
         &project.environments,
         files::string_to_path(&project.workdir).as_path(),
-    )
-    .unwrap_or(Environment::new_empty());
+    ) {
+        Ok(env) => env,
+        Err(e) => {
+            log::error(e.to_string());
+            return false;
+        }
+    };
+    let isolation = env.isolation.unwrap_or_default();
     vars.insert("env", env.values);
 
     // make sure required containers are running
@@ -56,7 +82,7 @@ pub async fn run_build(
     let steps: Vec<String> = vec![step.name.clone()];
 
     // make sure required builds have run successfully
-    if !run_steps(&steps, &project.build, &vars) {
+    if !run_workspace_steps(workspace, project, &steps, &vars, isolation) {
         return false;
     }
 
@@ -66,9 +92,9 @@ pub async fn run_build(
 /// Ensure that all build steps have been run successfully
 ///
 /// 1. First all build steps that are required for the given command are collected from the given vector
-/// of build steps.
+///    of build steps.
 /// 2. Then all of the found build steps are executed in sequence with the command outputs being printed
-/// to the standard output and standard error, as if the commands where executed manually.
+///    to the standard output and standard error, as if the commands where executed manually.
 /// 3. Only if all the build steps executed with exist status 0, true is returned, otherwise false.
 ///
 /// # Arguments
@@ -94,7 +120,7 @@ pub fn run_steps_for_command(
 ///
 /// 1. First all build steps that are required
 /// 2. Then all of the found build steps are executed in sequence with the command outputs being printed
-/// to the standard output and standard error, as if the commands where executed manually.
+///    to the standard output and standard error, as if the commands where executed manually.
 /// 3. Only if all the build steps executed with exist status 0, true is returned, otherwise false.
 ///
 /// # Arguments
@@ -113,16 +139,66 @@ pub fn run_steps(steps: &Vec<String>, build_steps: &BuildSteps, vars: &TemplateV
 
         let build_steps = get_build_steps(steps, build_steps, vars);
         for (step_name, shell) in build_steps {
-            for proc in shell {
-                log::print(format!("\tRunning build step: {}", step_name));
-                let result = shell::exec(&proc, vars.get("env"));
-                if result.is_err() {
-                    log::print("\tFailed to run Build Step!".to_string());
-                    return false;
-                } else if !result.unwrap().success() {
-                    log::error("\tBuild Step returned non-zero exit code!".to_string());
-                    return false;
-                }
+            if !run_shell(&step_name, &shell, vars, Isolation::Inherit) {
+                return false;
+            }
+        }
+        log::print(String::new());
+    }
+    true
+}
+
+/// Ensure that all build steps have been run successfully, including steps of other projects
+///
+/// Works like `run_steps`, but the build steps are looked up in the `project`,
+/// and references like `web:build` are looked up in the members of the `workspace`.
+/// Build steps of other projects are executed in the directory of their project
+/// and with the git variables of their project.
+/// The `exports` of a step are written after its processes succeeded.
+/// The `isolation` of the environment decides which system variables the processes inherit.
+///
+/// # Returns
+///
+/// * `true` if all build steps executed successfully
+/// * `false` if a step failed or a referenced project does not exist
+pub fn run_workspace_steps(
+    workspace: &Project,
+    project: &Project,
+    steps: &[String],
+    vars: &TemplateVariables,
+    isolation: Isolation,
+) -> bool {
+    if !steps.is_empty() {
+        log::print("Checking Build dependencies ..".to_string());
+
+        let mut found_steps: Vec<(&Project, &BuildStep)> = Vec::new();
+        if let Err(reference) =
+            find_workspace_steps(workspace, project, steps, &mut found_steps, &mut Vec::new())
+        {
+            log::error(format!(
+                "\tCould not find the project of build step '{}'",
+                reference
+            ));
+            return false;
+        }
+
+        for (step_project, step) in found_steps {
+            let mut step_vars = vars.clone();
+            if !ptr::eq(step_project, project) {
+                let step_workdir = files::string_to_path(&step_project.workdir);
+                step_vars.insert("git", git::fetch_repo_vars(step_workdir.as_path()));
+            }
+            let shell = if ptr::eq(step_project, workspace) {
+                create_shell_from_step(step, &step_vars, None)
+            } else {
+                let step_workdir = files::string_to_path(&step_project.workdir);
+                create_shell_from_step(step, &step_vars, Some(step_workdir.as_path()))
+            };
+            if !run_shell(&step.name, &shell, &step_vars, isolation) {
+                return false;
+            }
+            if !run_exports(step_project, step) {
+                return false;
             }
         }
         log::print(String::new());
@@ -130,6 +206,70 @@ pub fn run_steps(steps: &Vec<String>, build_steps: &BuildSteps, vars: &TemplateV
     true
 }
 
+/// Write the environments of the `exports` of a build step, relative to the directory of its project.
+fn run_exports(project: &Project, step: &BuildStep) -> bool {
+    let workdir = files::string_to_path(&project.workdir);
+    for environment_export in &step.exports {
+        log::print(format!(
+            "\tExporting environment '{}' to '{}'",
+            environment_export.environment, environment_export.path
+        ));
+        if let Err(e) =
+            export::write_export(environment_export, &project.environments, workdir.as_path())
+        {
+            log::error(format!("\tFailed to export environment: {}", e));
+            return false;
+        }
+    }
+    true
+}
+
+/// Resolve the order in which the wanted build steps and their dependencies are run.
+///
+/// Steps of other projects than `project` are named like `web:build`.
+/// Returns the reference of a step whose project could not be found.
+pub fn resolve_build_order(
+    workspace: &Project,
+    project: &Project,
+    steps: &[String],
+) -> Result<Vec<String>, String> {
+    let mut found_steps: Vec<(&Project, &BuildStep)> = Vec::new();
+    find_workspace_steps(workspace, project, steps, &mut found_steps, &mut Vec::new())?;
+    Ok(found_steps
+        .iter()
+        .map(|(step_project, step)| {
+            if ptr::eq(*step_project, project) {
+                step.name.clone()
+            } else {
+                format!("{}:{}", step_project.name, step.name)
+            }
+        })
+        .collect())
+}
+
+fn run_shell(
+    step_name: &str,
+    shell: &Vec<Process>,
+    vars: &TemplateVariables,
+    isolation: Isolation,
+) -> bool {
+    for proc in shell {
+        log::print(format!("\tRunning build step: {}", step_name));
+        match shell::exec(proc, vars.get("env"), isolation) {
+            Err(_) => {
+                log::print("\tFailed to run Build Step!".to_string());
+                return false;
+            }
+            Ok(status) if !status.success() => {
+                log::error("\tBuild Step returned non-zero exit code!".to_string());
+                return false;
+            }
+            _ => {}
+        }
+    }
+    true
+}
+
 /// Resolve a list of globs into a list of file paths
 ///
 /// The list of globs is resolved using the globs crate.
@@ -198,36 +338,90 @@ fn find_build_steps(wanted_steps: &Vec<String>, build_steps: &BuildSteps) -> Bui
     steps
 }
 
+/// Collect the wanted build steps and their dependencies across the projects of a workspace.
+///
+/// Dependencies are added before the steps that depend on them.
+/// Returns the reference of a step whose project could not be found.
+fn find_workspace_steps<'a>(
+    workspace: &'a Project,
+    project: &'a Project,
+    wanted_steps: &[String],
+    steps: &mut Vec<(&'a Project, &'a BuildStep)>,
+    visiting: &mut Vec<(&'a Project, &'a BuildStep)>,
+) -> Result<(), String> {
+    for wanted_step in wanted_steps {
+        let (project_name, step_name) = split_reference(wanted_step);
+        let step_project = if project_name.is_empty() {
+            project
+        } else {
+            workspace
+                .resolve_project(project_name)
+                .ok_or_else(|| wanted_step.clone())?
+        };
+
+        for step in step_project.build.iter().filter(|s| s.name == step_name) {
+            let is_current =
+                |(p, s): &(&Project, &BuildStep)| ptr::eq(*p, step_project) && ptr::eq(*s, step);
+            // Skip if the step is already in steps, or if we are already resolving it
+            if steps.iter().any(is_current) || visiting.iter().any(is_current) {
+                continue;
+            }
+            visiting.push((step_project, step));
+            find_workspace_steps(workspace, step_project, &step.depends_on, steps, visiting)?;
+            visiting.pop();
+            steps.push((step_project, step));
+        }
+    }
+    Ok(())
+}
+
 fn create_shell_from_steps(
     steps: &BuildSteps,
     vars: &TemplateVariables,
 ) -> Vec<(String, Vec<Process>)> {
     let mut shell: Vec<(String, Vec<Process>)> = Vec::new();
     for step in steps {
-        let procs = create_shell_from_step(step, vars);
+        let procs = create_shell_from_step(step, vars, None);
         shell.push((step.name.clone(), procs));
     }
     shell
 }
 
-fn create_shell_from_step(step: &BuildStep, vars: &TemplateVariables) -> Vec<Process> {
+/// Render the processes of a build step.
+///
+/// If a `workdir` is given, the processes and `input_files` are made relative to it.
+fn create_shell_from_step(
+    step: &BuildStep,
+    vars: &TemplateVariables,
+    workdir: Option<&Path>,
+) -> Vec<Process> {
     let mut template_vars = vars.clone();
     if !step.input_files.is_empty() {
-        template_vars.insert("build", create_build_vars(step));
+        template_vars.insert("build", create_build_vars(step, workdir));
     }
     step.shell
         .iter()
-        .map(|proc| template::render_process(proc, template_vars.as_value()))
-        .filter(|opt_proc| opt_proc.is_some())
-        .map(|opt_proc| opt_proc.unwrap())
+        .filter_map(|proc| template::render_process(proc, template_vars.as_value()))
+        .map(|proc| match workdir {
+            Some(dir) => proc.in_dir(dir),
+            None => proc,
+        })
         .collect::<Vec<Process>>()
 }
 
-fn create_build_vars(step: &BuildStep) -> HashMap<String, String> {
+fn create_build_vars(step: &BuildStep, workdir: Option<&Path>) -> HashMap<String, String> {
     let mut result: HashMap<String, String> = HashMap::new();
+    let globs = match workdir {
+        Some(dir) => step
+            .input_files
+            .iter()
+            .map(|glob| dir.join(glob).to_string_lossy().to_string())
+            .collect::<Vec<String>>(),
+        None => step.input_files.clone(),
+    };
     result.insert(
         "input_files".to_string(),
-        resolve_files_from_globs(&step.input_files).join(" "),
+        resolve_files_from_globs(&globs).join(" "),
     );
     result.insert("name".to_string(), step.name.clone());
     result
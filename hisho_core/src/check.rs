@@ -0,0 +1,677 @@
+// This file 'check.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+use crate::config_models::{
+    split_reference, EnvironmentSource, Process, Project, SecretSource, SourceFormat, VariableType,
+};
+use crate::environment::SystemVariable;
+use crate::{files, sources, template};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found by the static validation of a project.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Name of the project that contains the entity
+    pub project: String,
+    /// Kind of the entity, like `command` or `build step`
+    pub kind: &'static str,
+    /// Name of the entity
+    pub name: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} '{}' in project '{}': {}",
+            self.severity, self.kind, self.name, self.project, self.message
+        )
+    }
+}
+
+/// Statically validate a project and all of its members.
+///
+/// The following problems are reported:
+/// * references in `inherits`, `depends_on`, `depends_on_build`, `Command.environment`
+///   and `BuildStep.exports` that do not exist
+/// * cycles in `depends_on` of build steps and in `inherits` of environments
+/// * entities of the same kind with the same name
+/// * `sources` files and encrypted `secret_sources` files of environments that do not exist,
+///   except for optional sources, and patterns in `sources` that match no files, as a warning
+/// * typed `sources` without a path or a command, or with `select` for .env files
+/// * invalid entries in `system`, `unset` and `secret` of environments
+/// * invalid patterns and empty `Enum` types in the `schema` of environments
+/// * environment rules with unknown environments or invalid patterns,
+///   and rules after a rule without conditions, as a warning
+/// * invalid Liquid syntax in templated fields
+/// * use of the deprecated `Command.args` field, as a warning
+pub fn check_project(workspace: &Project) -> Vec<Problem> {
+    let mut problems: Vec<Problem> = Vec::new();
+    check_single_project(workspace, workspace, &mut problems);
+    for member in &workspace.member_projects {
+        check_single_project(workspace, member, &mut problems);
+    }
+    problems
+}
+
+fn check_single_project(workspace: &Project, project: &Project, problems: &mut Vec<Problem>) {
+    let mut report = |severity: Severity, kind: &'static str, name: &str, message: String| {
+        problems.push(Problem {
+            severity,
+            project: project.name.clone(),
+            kind,
+            name: name.to_string(),
+            message,
+        });
+    };
+
+    // duplicate names
+    let names: Vec<(&'static str, &String)> = project
+        .environments
+        .iter()
+        .map(|e| ("environment", &e.name))
+        .chain(project.containers.iter().map(|c| ("container", &c.name)))
+        .chain(project.build.iter().map(|b| ("build step", &b.name)))
+        .chain(project.services.iter().map(|s| ("service", &s.name)))
+        .chain(project.commands.iter().map(|c| ("command", &c.name)))
+        .collect();
+    let mut seen: HashSet<(&'static str, &String)> = HashSet::new();
+    for (kind, name) in names {
+        if !seen.insert((kind, name)) {
+            report(
+                Severity::Error,
+                kind,
+                name,
+                format!("the name '{}' is defined more than once", name),
+            );
+        }
+    }
+
+    let workdir = files::string_to_path(&project.workdir);
+    for env in &project.environments {
+        for parent in &env.inherits {
+            if !project.environments.iter().any(|e| e.name == *parent) {
+                report(
+                    Severity::Error,
+                    "environment",
+                    &env.name,
+                    format!("inherits the unknown environment '{}'", parent),
+                );
+            }
+        }
+        for entry in &env.system {
+            if let Err(e) = SystemVariable::parse(entry) {
+                report(
+                    Severity::Error,
+                    "environment",
+                    &env.name,
+                    format!("invalid system variable '{}': {}", entry, e),
+                );
+            }
+        }
+        for source in &env.sources {
+            for (severity, message) in check_source(source, &workdir) {
+                report(severity, "environment", &env.name, message);
+            }
+        }
+        for entry in &env.unset {
+            if let Err(e) = glob::Pattern::new(entry) {
+                report(
+                    Severity::Error,
+                    "environment",
+                    &env.name,
+                    format!("invalid unset pattern '{}': {}", entry, e),
+                );
+            }
+        }
+        for entry in &env.secret {
+            if let Err(e) = glob::Pattern::new(entry) {
+                report(
+                    Severity::Error,
+                    "environment",
+                    &env.name,
+                    format!("invalid secret pattern '{}': {}", entry, e),
+                );
+            }
+        }
+        for source in &env.secret_sources {
+            if let SecretSource::AgeFile { path, .. } = source {
+                if !workdir.join(path).is_file() {
+                    report(
+                        Severity::Error,
+                        "environment",
+                        &env.name,
+                        format!("the encrypted file '{}' does not exist", path),
+                    );
+                }
+            }
+        }
+        for (key, rules) in &env.schema {
+            if let Some(pattern) = &rules.pattern {
+                if let Err(e) = regex::Regex::new(pattern) {
+                    report(
+                        Severity::Error,
+                        "environment",
+                        &env.name,
+                        format!("invalid pattern for '{}' in the schema: {}", key, e),
+                    );
+                }
+            }
+            if rules.kind == Some(VariableType::Enum(Vec::new())) {
+                report(
+                    Severity::Error,
+                    "environment",
+                    &env.name,
+                    format!("the type of '{}' in the schema is an empty Enum", key),
+                );
+            }
+        }
+        for (key, value) in &env.values {
+            if let Err(e) = template::check_syntax(value) {
+                report(
+                    Severity::Error,
+                    "environment",
+                    &env.name,
+                    format!("invalid template in value '{}': {}", key, e),
+                );
+            }
+        }
+    }
+
+    let mut unconditional: Option<usize> = None;
+    for (index, rule) in project.environment_rules.iter().enumerate() {
+        let name = (index + 1).to_string();
+        if !project
+            .environments
+            .iter()
+            .any(|e| e.name == rule.environment)
+        {
+            report(
+                Severity::Error,
+                "environment rule",
+                &name,
+                format!("uses the unknown environment '{}'", rule.environment),
+            );
+        }
+        let patterns = rule
+            .branch
+            .iter()
+            .chain(rule.hostname.iter())
+            .chain(rule.variables.values());
+        for pattern in patterns {
+            if let Err(e) = glob::Pattern::new(pattern) {
+                report(
+                    Severity::Error,
+                    "environment rule",
+                    &name,
+                    format!("invalid pattern '{}': {}", pattern, e),
+                );
+            }
+        }
+        if let Some(first) = unconditional {
+            report(
+                Severity::Warning,
+                "environment rule",
+                &name,
+                format!("is never used, rule {} always matches", first + 1),
+            );
+        } else if rule.branch.is_none()
+            && rule.hostname.is_none()
+            && rule.variables.is_empty()
+            && rule.os.is_none()
+        {
+            unconditional = Some(index);
+        }
+    }
+
+    for container in &project.containers {
+        if let Err(e) = template::check_syntax(&container.name) {
+            report(
+                Severity::Error,
+                "container",
+                &container.name,
+                format!("invalid template in name: {}", e),
+            );
+        }
+    }
+
+    for step in &project.build {
+        for reference in &step.depends_on {
+            if let Some(message) = check_build_reference(workspace, project, reference) {
+                report(Severity::Error, "build step", &step.name, message);
+            }
+        }
+        for message in check_processes(&step.shell) {
+            report(Severity::Error, "build step", &step.name, message);
+        }
+        for environment_export in &step.exports {
+            if !project
+                .environments
+                .iter()
+                .any(|e| e.name == environment_export.environment)
+            {
+                report(
+                    Severity::Error,
+                    "build step",
+                    &step.name,
+                    format!(
+                        "exports the unknown environment '{}'",
+                        environment_export.environment
+                    ),
+                );
+            }
+        }
+    }
+
+    for cmd in &project.commands {
+        if !cmd.environment.is_empty()
+            && !project
+                .environments
+                .iter()
+                .any(|e| e.name == cmd.environment)
+        {
+            report(
+                Severity::Error,
+                "command",
+                &cmd.name,
+                format!("uses the unknown environment '{}'", cmd.environment),
+            );
+        }
+        for reference in &cmd.depends_on_build {
+            if let Some(message) = check_build_reference(workspace, project, reference) {
+                report(Severity::Error, "command", &cmd.name, message);
+            }
+        }
+        for message in check_processes(&cmd.shell) {
+            report(Severity::Error, "command", &cmd.name, message);
+        }
+        #[allow(deprecated)]
+        if !cmd.args.is_empty() {
+            report(
+                Severity::Warning,
+                "command",
+                &cmd.name,
+                "the field 'args' is deprecated and has no effect".to_string(),
+            );
+        }
+    }
+
+    // cycles
+    let build_graph: Vec<(&String, &Vec<String>)> = project
+        .build
+        .iter()
+        .map(|step| (&step.name, &step.depends_on))
+        .collect();
+    for cycle in find_cycles(&build_graph) {
+        report(
+            Severity::Error,
+            "build step",
+            &cycle[0],
+            format!("depends on itself: {}", cycle.join(" -> ")),
+        );
+    }
+    let env_graph: Vec<(&String, &Vec<String>)> = project
+        .environments
+        .iter()
+        .map(|env| (&env.name, &env.inherits))
+        .collect();
+    for cycle in find_cycles(&env_graph) {
+        report(
+            Severity::Error,
+            "environment",
+            &cycle[0],
+            format!("inherits from itself: {}", cycle.join(" -> ")),
+        );
+    }
+}
+
+/// Check that a build step reference like `debug` or `web:build` exists.
+fn check_build_reference(
+    workspace: &Project,
+    project: &Project,
+    reference: &str,
+) -> Option<String> {
+    let (project_name, step_name) = split_reference(reference);
+    let target = if project_name.is_empty() {
+        project
+    } else {
+        match workspace.resolve_project(project_name) {
+            Some(target) => target,
+            None => {
+                return Some(format!(
+                    "depends on the build step '{}' of the unknown project '{}'",
+                    reference, project_name
+                ))
+            }
+        }
+    };
+    if target.build.iter().any(|step| step.name == step_name) {
+        None
+    } else {
+        Some(format!("depends on the unknown build step '{}'", reference))
+    }
+}
+
+fn check_source(source: &EnvironmentSource, workdir: &Path) -> Vec<(Severity, String)> {
+    let (path, typed) = match source {
+        EnvironmentSource::Path(path) => (Some(path), None),
+        EnvironmentSource::Typed(typed) => (typed.path.as_ref(), Some(typed)),
+    };
+    let mut messages: Vec<(Severity, String)> = Vec::new();
+    if let Some(typed) = typed {
+        if typed.path.is_some() == typed.exec.is_some() {
+            messages.push((
+                Severity::Error,
+                format!(
+                    "the source '{}' must have either a path or a command",
+                    source.describe()
+                ),
+            ));
+            return messages;
+        }
+        let format = match (typed.format, &typed.path) {
+            (Some(format), _) => format,
+            (None, Some(path)) => SourceFormat::from_path(Path::new(path)),
+            (None, None) => SourceFormat::DotEnv,
+        };
+        if typed.select.is_some() && format == SourceFormat::DotEnv {
+            messages.push((
+                Severity::Error,
+                format!(
+                    "the source '{}' is a .env file, which does not support 'select'",
+                    source.describe()
+                ),
+            ));
+        }
+        if typed.optional {
+            return messages;
+        }
+    }
+    let Some(path) = path else {
+        return messages;
+    };
+    if sources::is_pattern(path) {
+        if sources::source_files(source, workdir).is_empty() {
+            messages.push((
+                Severity::Warning,
+                format!("the source pattern '{}' matches no files", path),
+            ));
+        }
+    } else if !workdir.join(path).is_file() {
+        messages.push((
+            Severity::Error,
+            format!("the source file '{}' does not exist", path),
+        ));
+    }
+    messages
+}
+
+fn check_processes(processes: &[Process]) -> Vec<String> {
+    let mut messages: Vec<String> = Vec::new();
+    for process in processes {
+        let mut fields: Vec<&String> = process.args.iter().collect();
+        fields.push(&process.cwd);
+        if cfg!(feature = "allow_unsafe_command_templates") {
+            fields.push(&process.command);
+        }
+        for field in fields {
+            if let Err(e) = template::check_syntax(field) {
+                messages.push(format!(
+                    "invalid template in process '{}': {}",
+                    process.command, e
+                ));
+            }
+        }
+    }
+    messages
+}
+
+/// Find all cycles in a graph of names and the names they depend on.
+///
+/// Each cycle is returned once, starting and ending with the same name.
+/// Dependencies on unknown names are ignored.
+pub fn find_cycles(graph: &[(&String, &Vec<String>)]) -> Vec<Vec<String>> {
+    let edges: HashMap<&String, &Vec<String>> = graph.iter().cloned().collect();
+    let mut cycles: Vec<Vec<String>> = Vec::new();
+    let mut reported: HashSet<Vec<String>> = HashSet::new();
+    let mut done: HashSet<&String> = HashSet::new();
+
+    fn visit<'a>(
+        node: &'a String,
+        edges: &HashMap<&'a String, &'a Vec<String>>,
+        path: &mut Vec<&'a String>,
+        done: &mut HashSet<&'a String>,
+        cycles: &mut Vec<Vec<String>>,
+        reported: &mut HashSet<Vec<String>>,
+    ) {
+        if let Some(pos) = path.iter().position(|n| *n == node) {
+            let mut cycle: Vec<String> = path[pos..].iter().map(|n| n.to_string()).collect();
+            // use the smallest rotation to report every cycle only once
+            let mut key = cycle.clone();
+            let min = (0..key.len()).min_by_key(|i| &key[*i]).unwrap_or(0);
+            key.rotate_left(min);
+            if reported.insert(key) {
+                cycle.push(node.to_string());
+                cycles.push(cycle);
+            }
+            return;
+        }
+        if done.contains(node) {
+            return;
+        }
+        path.push(node);
+        if let Some(dependencies) = edges.get(node) {
+            for dependency in dependencies.iter() {
+                if let Some((next, _)) = edges.get_key_value(dependency) {
+                    visit(next, edges, path, done, cycles, reported);
+                }
+            }
+        }
+        path.pop();
+        done.insert(node);
+    }
+
+    for (node, _) in graph {
+        visit(
+            node,
+            &edges,
+            &mut Vec::new(),
+            &mut done,
+            &mut cycles,
+            &mut reported,
+        );
+    }
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::parse_project;
+    use std::path::Path;
+
+    fn check(data: &str) -> Vec<String> {
+        let project = parse_project(Path::new("hisho.ron"), data).unwrap();
+        check_project(&project)
+            .iter()
+            .map(|p| p.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn report_dangling_references() {
+        let problems = check(
+            r#"Project(
+                name: "test",
+                environments: [Environment(name: "child", inherits: ["parnet"])],
+                build: [BuildStep(name: "debug", shell: [], depends_on: ["versoin"])],
+                commands: [Command(name: "run", environment: "chlid", depends_on_build: ["debgu"])],
+            )"#,
+        );
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems.contains(&"error: environment 'child' in project 'test': inherits the unknown environment 'parnet'".to_string()));
+        assert!(problems.contains(&"error: build step 'debug' in project 'test': depends on the unknown build step 'versoin'".to_string()));
+        assert!(problems.contains(
+            &"error: command 'run' in project 'test': uses the unknown environment 'chlid'"
+                .to_string()
+        ));
+        assert!(problems.contains(
+            &"error: command 'run' in project 'test': depends on the unknown build step 'debgu'"
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn report_cycles_once() {
+        let problems = check(
+            r#"Project(
+                name: "test",
+                environments: [
+                    Environment(name: "a", inherits: ["b"]),
+                    Environment(name: "b", inherits: ["a"]),
+                ],
+                build: [
+                    BuildStep(name: "x", shell: [], depends_on: ["x"]),
+                ],
+            )"#,
+        );
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems.contains(
+            &"error: environment 'a' in project 'test': inherits from itself: a -> b -> a"
+                .to_string()
+        ));
+        assert!(problems.contains(
+            &"error: build step 'x' in project 'test': depends on itself: x -> x".to_string()
+        ));
+    }
+
+    #[test]
+    fn report_invalid_templates_and_deprecations() {
+        let problems = check(
+            r#"Project(
+                name: "test",
+                commands: [
+                    Command(name: "run", args: {"a": "b"}, shell: [Process(command: "echo", args: ["{{ env.A "])]),
+                    Command(name: "run"),
+                ],
+            )"#,
+        );
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("error: command 'run' in project 'test': invalid template")));
+        assert!(problems.contains(
+            &"warning: command 'run' in project 'test': the field 'args' is deprecated and has no effect".to_string()
+        ));
+        assert!(problems.contains(
+            &"error: command 'run' in project 'test': the name 'run' is defined more than once"
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn report_invalid_schemas() {
+        let problems = check(
+            r#"Project(
+                name: "test",
+                environments: [Environment(name: "dev", schema: {
+                    "PORT": (type: Some(Int), pattern: Some("[0-9")),
+                    "MODE": (type: Some(Enum([]))),
+                })],
+            )"#,
+        );
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems.iter().any(|p| p.starts_with(
+            "error: environment 'dev' in project 'test': invalid pattern for 'PORT' in the schema"
+        )));
+        assert!(problems.contains(
+            &"error: environment 'dev' in project 'test': the type of 'MODE' in the schema is an empty Enum"
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn report_invalid_environment_rules() {
+        let problems = check(
+            r#"Project(
+                name: "test",
+                environments: [Environment(name: "dev")],
+                environment_rules: [
+                    EnvironmentRule(environment: "stagign", branch: "release/[1"),
+                    EnvironmentRule(environment: "dev"),
+                    EnvironmentRule(environment: "dev", os: "linux"),
+                ],
+            )"#,
+        );
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems.contains(
+            &"error: environment rule '1' in project 'test': uses the unknown environment 'stagign'"
+                .to_string()
+        ));
+        assert!(problems.iter().any(|p| p.starts_with(
+            "error: environment rule '1' in project 'test': invalid pattern 'release/[1'"
+        )));
+        assert!(problems.contains(
+            &"warning: environment rule '3' in project 'test': is never used, rule 2 always matches"
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn report_invalid_sources() {
+        let problems = check(
+            r#"Project(
+                name: "test",
+                environments: [Environment(name: "dev", sources: [
+                    "missing.env",
+                    "missing/*.env",
+                    Source(path: "a.env", select: "database"),
+                    Source(format: Json),
+                    Source(path: "optional.json", optional: true),
+                ])],
+            )"#,
+        );
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems.contains(
+            &"error: environment 'dev' in project 'test': the source file 'missing.env' does not exist"
+                .to_string()
+        ));
+        assert!(problems.contains(
+            &"warning: environment 'dev' in project 'test': the source pattern 'missing/*.env' matches no files"
+                .to_string()
+        ));
+        assert!(problems.contains(
+            &"error: environment 'dev' in project 'test': the source 'a.env' is a .env file, which does not support 'select'"
+                .to_string()
+        ));
+        assert!(problems.contains(
+            &"error: environment 'dev' in project 'test': the source '' must have either a path or a command"
+                .to_string()
+        ));
+    }
+}
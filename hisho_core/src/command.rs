@@ -6,8 +6,15 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::config_models::{Command, Environment, Process, Project};
-use crate::environment::fetch_environment;
+use std::collections::HashMap;
+use std::process::ExitStatus;
+use std::ptr;
+
+use crate::config_models::{Command, Process, Project};
+use crate::environment::{
+    fetch_environment, fetch_environment_with_overrides, EnvironmentOverrides,
+};
+use crate::selection::{self, Choice};
 use crate::template::TemplateVariables;
 use crate::{build_tool, containers, files, log, service, shell, template};
 
@@ -16,15 +23,57 @@ pub async fn run_command(
     project: &Project,
     cmd: &Command,
     default_vars: &TemplateVariables,
-    argv: &Vec<String>,
+    argv: &[String],
+) -> bool {
+    run_workspace_command(
+        project,
+        project,
+        cmd,
+        default_vars,
+        argv,
+        &EnvironmentOverrides::default(),
+    )
+    .await
+}
+
+/// Run a command of a project that is part of a workspace
+///
+/// The `project` is either the `workspace` itself or one of its members.
+/// Build steps of other projects, like `web:build`, are resolved against the members of the `workspace`.
+/// Processes of member projects are executed relative to the directory of the member.
+///
+/// The environment of the `overrides` replaces the environment of the command.
+/// A command without an environment uses the default environment of the project or the first matching
+/// environment rule, see `selection::select_environment`.
+pub async fn run_workspace_command(
+    workspace: &Project,
+    project: &Project,
+    cmd: &Command,
+    default_vars: &TemplateVariables,
+    argv: &[String],
+    overrides: &EnvironmentOverrides,
 ) -> bool {
     let mut vars = default_vars.clone();
-    let env = fetch_environment(
-        cmd.environment.clone().as_str(),
+    let workdir = files::string_to_path(&project.workdir);
+    let selection =
+        selection::select_environment(project, overrides.environment.as_deref(), &cmd.environment);
+    if let Choice::Rule(_) = selection.choice {
+        log::print(format!("Using {}", selection));
+    }
+    let environment = selection.environment;
+    let env = match fetch_environment_with_overrides(
+        &environment,
         &project.environments,
-        files::string_to_path(&project.workdir).as_path(),
-    )
-    .unwrap_or(Environment::new_empty());
+        &workdir,
+        overrides,
+    ) {
+        Ok((env, _)) => env,
+        Err(e) => {
+            log::error(e.to_string());
+            return false;
+        }
+    };
+    let isolation = env.isolation.unwrap_or_default();
     vars.insert("env", env.values);
 
     // make sure required containers are running
@@ -38,7 +87,8 @@ pub async fn run_command(
     }
 
     // make sure required builds have run successfully
-    if !build_tool::run_steps_for_command(cmd, &project.build, &vars) {
+    if !build_tool::run_workspace_steps(workspace, project, &cmd.depends_on_build, &vars, isolation)
+    {
         return false;
     }
 
@@ -53,13 +103,62 @@ pub async fn run_command(
         if let Some(rendered_command) =
             template::render_process_with_argv(shell_cmd, vars.as_value(), argv)
         {
-            rendered_commands.push(rendered_command);
+            if ptr::eq(project, workspace) {
+                rendered_commands.push(rendered_command);
+            } else {
+                rendered_commands.push(rendered_command.in_dir(workdir.as_path()));
+            }
         }
     }
 
     for rendered_command in &rendered_commands {
-        let _ = shell::exec(rendered_command, vars.get("env"));
+        let _ = shell::exec(rendered_command, vars.get("env"), isolation);
     }
 
     true
 }
+
+/// Run a process within an environment of a project, without a `Command`
+///
+/// Like for a command, the containers and services of the project must be running first.
+/// The `extra_env` is added to the variables of the environment, like the prompt of `shell::interactive_shell`.
+///
+/// # Returns
+///
+/// * the exit status of the process
+/// * `None` if the environment could not be resolved, a precondition failed or the process could not be executed
+pub async fn run_in_environment(
+    project: &Project,
+    environment: &str,
+    process: &Process,
+    extra_env: HashMap<String, String>,
+    default_vars: &TemplateVariables,
+) -> Option<ExitStatus> {
+    let mut vars = default_vars.clone();
+    let mut env = match fetch_environment(
+        environment,
+        &project.environments,
+        files::string_to_path(&project.workdir).as_path(),
+    ) {
+        Ok(env) => env,
+        Err(e) => {
+            log::error(e.to_string());
+            return None;
+        }
+    };
+    let isolation = env.isolation.unwrap_or_default();
+    env.values.extend(extra_env);
+    vars.insert("env", env.values);
+
+    // make sure required containers are running
+    if !containers::start_containers(&project.containers, &vars).await {
+        return None;
+    }
+
+    // make sure required services are running
+    if !service::are_running(&project.services).await {
+        return None;
+    }
+
+    shell::exec(process, vars.get("env"), isolation).ok()
+}
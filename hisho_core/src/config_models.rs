@@ -6,42 +6,350 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The top-level of a project file, it contains everything.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Project {
+    /// Human readable name of the Project
     pub name: String,
+    /// Version of the project file format, files without a version are version 1
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+    /// Other project files to merge into this project
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<String>,
+    /// Member projects of this workspace, paths to project files or directories
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<String>,
+    /// The Environments for process execution
     #[serde(default)]
     pub environments: Environments,
+    /// Rules to choose the Environment for commands that do not set one, the first matching rule wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub environment_rules: Vec<EnvironmentRule>,
+    /// Docker Containers that must be running
     #[serde(default)]
     pub containers: Containers,
+    /// Steps to build a thing
     #[serde(default)]
     pub build: BuildSteps,
+    /// Services that must be running
     #[serde(default)]
     pub services: Services,
+    /// Commands that can be run
     #[serde(default)]
     pub commands: Commands,
 
     // this is a runtime variable
     #[serde(skip)]
     pub workdir: String,
+    // this is a runtime variable, the path of the loaded project file
+    #[serde(skip)]
+    pub file: String,
+    // this is a runtime variable, the loaded projects from `members`
+    #[serde(skip)]
+    pub member_projects: Vec<Project>,
+}
+
+fn legacy_version() -> u32 {
+    1
+}
+
+impl Project {
+    /// Find a member project of this workspace by its name.
+    pub fn find_member(&self, name: &str) -> Option<&Project> {
+        self.member_projects
+            .iter()
+            .find(|member| member.name == name)
+    }
+
+    /// Resolve a project reference like `web` of `web:build`.
+    ///
+    /// An empty reference, or the name of this project, resolves to this project.
+    pub fn resolve_project(&self, name: &str) -> Option<&Project> {
+        if name.is_empty() || name == self.name {
+            Some(self)
+        } else {
+            self.find_member(name)
+        }
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Split a reference like `web:build` into the project name and the entity name.
+///
+/// References without a project return an empty project name.
+pub fn split_reference(reference: &str) -> (&str, &str) {
+    match reference.split_once(':') {
+        Some((project, name)) => (project, name),
+        None => ("", reference),
+    }
+}
+
+/// Environment variables for templating and process execution.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct Environment {
+    /// Human readable name of the Environment
     pub name: String,
+    /// List of variables to copy from the system, like `HOME`, `AWS_*`, `HOST_HOME=HOME`, `EDITOR:-vi` or `API_TOKEN!`
     #[serde(default)]
     pub system: Vec<String>,
+    /// List of other Environments to copy variables from
     #[serde(default)]
     pub inherits: Vec<String>,
+    /// Map of string-value variables
     #[serde(default)]
     pub values: HashMap<String, String>,
+    /// List of .env files, glob patterns or typed sources to copy from
     #[serde(default)]
-    pub sources: Vec<String>,
+    pub sources: Vec<EnvironmentSource>,
+    /// List of secret providers to copy from, their values are never printed
+    #[serde(default)]
+    pub secret_sources: Vec<SecretSource>,
+    /// List of inherited variables to remove, names or patterns like `AWS_*`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unset: Vec<String>,
+    /// Map of entries to add in front of list variables like `PATH`, joined with the separator of the platform
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub prepend: HashMap<String, Vec<String>>,
+    /// Map of entries to add at the end of list variables like `PATH`, joined with the separator of the platform
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub append: HashMap<String, Vec<String>>,
+    /// List of variables whose values are never printed, names or patterns like `*_KEY`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secret: Vec<String>,
+    /// Which variables of the system processes inherit, taken from the inherited Environments when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isolation: Option<Isolation>,
+    /// Map of rules for variables, checked after all variables are merged, inherited from the parent Environments
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub schema: HashMap<String, VariableSchema>,
 }
 pub type Environments = Vec<Environment>;
 
+/// A rule to choose an Environment, it matches if all of its conditions match.
+///
+/// A rule without conditions always matches.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct EnvironmentRule {
+    /// Name of the Environment to use
+    pub environment: String,
+    /// Glob pattern for the current git branch, like `release/*`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    /// Glob pattern for the name of the host, like `ci-*`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Map of system variables to glob patterns for their values, like `{"CI": "true"}`, `*` matches any set variable
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub variables: HashMap<String, String>,
+    /// The operating system, like `linux`, `macos` or `windows`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+}
+
+/// Which variables of the system are passed to the processes of build steps and commands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub enum Isolation {
+    /// Processes inherit all variables of the system
+    #[default]
+    Inherit,
+    /// Processes only get the variables of the Environment, use `system` to copy variables
+    Clear,
+    /// Like `Clear`, but `PATH`, `HOME` and `TERM` are always copied from the system
+    Minimal,
+}
+
+/// An entry of `sources`, the path of a .env file or a typed source.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum EnvironmentSource {
+    /// Path of a .env file or a glob pattern, relative to the project
+    Path(String),
+    /// A file in another format or the output of a command
+    Typed(TypedSource),
+}
+
+impl EnvironmentSource {
+    /// Describe the source for messages, the path or the command
+    pub fn describe(&self) -> String {
+        match self {
+            EnvironmentSource::Path(path) => path.clone(),
+            EnvironmentSource::Typed(source) => match (&source.path, &source.exec) {
+                (_, Some(process)) => format!("command '{}'", process.command),
+                (Some(path), None) => path.clone(),
+                (None, None) => String::new(),
+            },
+        }
+    }
+}
+
+impl From<&str> for EnvironmentSource {
+    fn from(path: &str) -> Self {
+        EnvironmentSource::Path(path.to_string())
+    }
+}
+
+// untagged enums can not be read from RON, as it drops the names of structs in `deserialize_any`
+impl<'de> Deserialize<'de> for EnvironmentSource {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SourceVisitor;
+
+        impl<'de> Visitor<'de> for SourceVisitor {
+            type Value = EnvironmentSource;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                write!(formatter, "the path of a .env file or a Source")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(EnvironmentSource::Path(value.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                TypedSource::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(EnvironmentSource::Typed)
+            }
+        }
+
+        deserializer.deserialize_any(SourceVisitor)
+    }
+}
+
+/// A source of variables in another format than .env, or the output of a command.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename = "Source")]
+pub struct TypedSource {
+    /// Path of the file or a glob pattern, relative to the project
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Command to run instead of reading a file, its output is parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exec: Option<Process>,
+    /// Format of the file or output, detected from the file extension and `DotEnv` for commands when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<SourceFormat>,
+    /// Prefix for the names of all variables, like `APP` for `APP_DATABASE_HOST`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Dot-separated key of the part of a JSON, YAML or TOML document to load, like `services.web`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<String>,
+    /// Skip the source without an error if the file does not exist or the command fails
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+}
+
+/// Format of a source of variables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub enum SourceFormat {
+    /// `.env` file, like `KEY=value`
+    DotEnv,
+    /// JSON document, nested keys are joined with `_`
+    Json,
+    /// YAML document, nested keys are joined with `_`
+    Yaml,
+    /// TOML document, nested keys are joined with `_`
+    Toml,
+}
+
+impl SourceFormat {
+    /// Detect the format from the extension of a path, `DotEnv` for unknown extensions
+    pub fn from_path(path: &Path) -> SourceFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => SourceFormat::Json,
+            Some("yaml" | "yml") => SourceFormat::Yaml,
+            Some("toml") => SourceFormat::Toml,
+            _ => SourceFormat::DotEnv,
+        }
+    }
+}
+
+/// Rules for the value of a variable in an Environment.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct VariableSchema {
+    /// The variable must be set
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub required: bool,
+    /// Type of the value, only checked if the variable is set
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<VariableType>,
+    /// Regular expression the whole value must match, only checked if the variable is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// Human readable description of the variable, shown when it is invalid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Type of the value of a variable.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub enum VariableType {
+    /// A whole number, like `-1` or `8080`
+    Int,
+    /// `true`, `false`, `1`, `0`, `yes`, `no`, `on` or `off`, ignoring case
+    Bool,
+    /// An absolute URL with a scheme, like `postgres://localhost/app`
+    Url,
+    /// A path that exists, relative to the project
+    Path,
+    /// One of the listed values
+    Enum(Vec<String>),
+}
+
+/// A provider of secret values for an Environment.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub enum SecretSource {
+    /// A .env file encrypted with age, decrypted by the `age` command with the identity from `~/.config/hisho/age.txt`
+    AgeFile {
+        /// Path of the encrypted file, relative to the project
+        path: String,
+        /// Path of the identity file to use instead of `~/.config/hisho/age.txt`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+    },
+    /// The output of a command, like `pass show db/password`, without the trailing newline
+    Command {
+        /// Name of the variable
+        name: String,
+        /// Path of the executable
+        command: String,
+        /// List of arguments for the executable
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// The content of a file, without the trailing newline
+    File {
+        /// Name of the variable
+        name: String,
+        /// Path of the file, relative to the project or starting with `~`
+        path: String,
+    },
+}
+
+impl SecretSource {
+    /// Short description of the provider, used in messages
+    pub fn describe(&self) -> String {
+        match self {
+            SecretSource::AgeFile { path, .. } => format!("age file '{}'", path),
+            SecretSource::Command { name, command, .. } => {
+                format!("command '{}' for '{}'", command, name)
+            }
+            SecretSource::File { name, path } => format!("file '{}' for '{}'", path, name),
+        }
+    }
+}
+
+impl Isolation {
+    /// Variables of the system that are always passed to processes with `Minimal` isolation
+    pub const MINIMAL_BASELINE: [&'static str; 3] = ["PATH", "HOME", "TERM"];
+}
+
 impl Environment {
     pub fn new_empty() -> Environment {
         Environment {
@@ -50,6 +358,13 @@ impl Environment {
             inherits: Vec::new(),
             values: HashMap::new(),
             sources: Vec::new(),
+            secret_sources: Vec::new(),
+            unset: Vec::new(),
+            prepend: HashMap::new(),
+            append: HashMap::new(),
+            secret: Vec::new(),
+            isolation: None,
+            schema: HashMap::new(),
         }
     }
     pub fn new(name: &str, inherits: Vec<String>, values: HashMap<String, String>) -> Environment {
@@ -59,62 +374,168 @@ impl Environment {
             inherits,
             values,
             sources: Vec::new(),
+            secret_sources: Vec::new(),
+            unset: Vec::new(),
+            prepend: HashMap::new(),
+            append: HashMap::new(),
+            secret: Vec::new(),
+            isolation: None,
+            schema: HashMap::new(),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A Docker Container that must be running.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Container {
+    /// Docker Name or ID of the container
     pub name: String,
 }
 pub type Containers = Vec<Container>;
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A Command that can be run.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Command {
+    /// Name of the command
     pub name: String,
+    /// Environment to load for the process execution
     #[serde(default)]
     pub environment: String,
+    /// List of Processes to execute in order
     #[serde(default)]
     pub shell: Vec<Process>,
-    #[serde(default)]
+    /// Deprecated, this field has no effect
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     #[deprecated(note = "this field is no longer unused")]
     pub args: HashMap<String, String>,
+    /// List of build steps to run before running any shell
     #[serde(default)]
     pub depends_on_build: Vec<String>,
 }
 pub type Commands = Vec<Command>;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// A Process to execute.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct Process {
+    /// Path to the executable to run
     pub command: String,
+    /// List of arguments for the executable, can be templated
     #[serde(default)]
     pub args: Vec<String>,
+    /// Path where the command should be executed at (working directory)
     #[serde(default)]
     pub cwd: String,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl Process {
+    /// Create a copy of the process that runs relative to the given directory.
+    ///
+    /// An empty `cwd` becomes the directory itself, a relative `cwd` is joined onto it.
+    pub fn in_dir(&self, dir: &Path) -> Process {
+        let cwd = if self.cwd.is_empty() {
+            dir.to_path_buf()
+        } else {
+            dir.join(&self.cwd)
+        };
+        Process {
+            command: self.command.clone(),
+            args: self.args.clone(),
+            cwd: cwd.to_string_lossy().to_string(),
+        }
+    }
+}
+
+/// A build step that must succeed.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct BuildStep {
+    /// Name of the build
     pub name: String,
+    /// List of Process to execute
+    #[serde(default)]
     pub shell: Vec<Process>,
+    /// List of build-steps to depend on
     #[serde(default)]
     pub depends_on: Vec<String>,
+    /// List of globs to collect file paths for `input_files`
     #[serde(default)]
     pub input_files: Vec<String>,
+    /// List of files to write Environments to, after the processes succeeded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exports: Vec<EnvironmentExport>,
 }
 pub type BuildSteps = Vec<BuildStep>;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// A file the variables of an Environment are written to.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct EnvironmentExport {
+    /// Name of the Environment to write
+    pub environment: String,
+    /// Format of the file
+    pub format: ExportFormat,
+    /// Path of the file, relative to the project
+    pub path: String,
+}
+
+/// Format of an exported Environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub enum ExportFormat {
+    /// `.env` file with quoted values, like `KEY="value"`
+    DotEnv,
+    /// POSIX shell script, like `export KEY='value'`
+    Sh,
+    /// fish shell script, like `set -gx KEY 'value'`
+    Fish,
+    /// JSON object of all variables
+    Json,
+    /// File for `docker run --env-file`, like `KEY=value`; values can not contain line breaks
+    DockerEnv,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 5] = [
+        ExportFormat::DotEnv,
+        ExportFormat::Sh,
+        ExportFormat::Fish,
+        ExportFormat::Json,
+        ExportFormat::DockerEnv,
+    ];
+
+    /// Name of the format on the command line, like `docker-env`
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::DotEnv => "dotenv",
+            ExportFormat::Sh => "sh",
+            ExportFormat::Fish => "fish",
+            ExportFormat::Json => "json",
+            ExportFormat::DockerEnv => "docker-env",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        ExportFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == name)
+    }
+}
+
+/// A Service that must be reachable.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct Service {
+    /// Human readable name of the Service
     pub name: String,
+    /// Protocol of the service
     pub protocol: ServiceProtocol,
+    /// Unified Resource Identifier for the service
     pub uri: String,
 }
 pub type Services = Vec<Service>;
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+/// How Hisho connects to the URI of a Service.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema)]
 pub enum ServiceProtocol {
+    /// Use HTTP/HTTPS; A HTTP `GET` Request must respond with success
     HTTP,
+    /// Use raw TCP; A TCP connection must be established for success
     TCP,
 }
 
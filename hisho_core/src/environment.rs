@@ -6,106 +6,1383 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
 
-use crate::config_models::{Environment, Environments};
+use serde::Serialize;
+
+use crate::config_models::{
+    Environment, EnvironmentSource, Environments, Isolation, SecretSource, TypedSource,
+    VariableSchema, VariableType,
+};
 use crate::log;
-use crate::template;
+use crate::secrets;
+use crate::sources;
+use crate::template::{self, ValuePart};
+
+/// Errors that can occur while resolving an environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvironmentError {
+    /// The environment does not exist, optionally with the environment that inherits it
+    NotFound {
+        name: String,
+        inherited_by: Option<String>,
+    },
+    /// An environment inherits itself, the chain starts at the requested environment
+    Cycle { chain: Vec<String> },
+    /// The template of a variable references a variable that does not exist
+    MissingVariable { key: String, missing: String },
+    /// The template of a variable references itself, directly or through other variables
+    VariableCycle { chain: Vec<String> },
+    /// The template of a variable could not be rendered
+    Render { key: String, message: String },
+    /// An entry of `system` could not be parsed
+    InvalidSystemVariable {
+        environment: String,
+        entry: String,
+        message: String,
+    },
+    /// A `system` variable marked as required is not set
+    MissingSystemVariable {
+        environment: String,
+        variable: String,
+    },
+    /// A secret provider of `secret_sources` could not be loaded
+    Secret {
+        environment: String,
+        source: String,
+        message: String,
+    },
+    /// An entry of `sources` that is not optional could not be loaded
+    Source {
+        environment: String,
+        message: String,
+    },
+    /// Variables do not match the `schema` of the environment, with one message per violation
+    Schema {
+        environment: String,
+        violations: Vec<String>,
+    },
+}
+
+impl fmt::Display for EnvironmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvironmentError::NotFound {
+                name,
+                inherited_by: None,
+            } => write!(f, "Could not find environment '{}'", name),
+            EnvironmentError::NotFound {
+                name,
+                inherited_by: Some(child),
+            } => write!(
+                f,
+                "Could not find environment '{}', inherited by '{}'",
+                name, child
+            ),
+            EnvironmentError::Cycle { chain } => write!(
+                f,
+                "The environment '{}' inherits from itself: {}",
+                chain.last().map(String::as_str).unwrap_or_default(),
+                chain.join(" -> ")
+            ),
+            EnvironmentError::MissingVariable { key, missing } => write!(
+                f,
+                "The value of '{}' references the unknown variable '{}'",
+                key, missing
+            ),
+            EnvironmentError::VariableCycle { chain } => write!(
+                f,
+                "The value of '{}' references itself: {}",
+                chain.first().map(String::as_str).unwrap_or_default(),
+                chain.join(" -> ")
+            ),
+            EnvironmentError::Render { key, message } => {
+                write!(f, "Could not render the value of '{}': {}", key, message)
+            }
+            EnvironmentError::InvalidSystemVariable {
+                environment,
+                entry,
+                message,
+            } => write!(
+                f,
+                "The environment '{}' has the invalid system variable '{}': {}",
+                environment, entry, message
+            ),
+            EnvironmentError::MissingSystemVariable {
+                environment,
+                variable,
+            } => write!(
+                f,
+                "The environment '{}' requires the system variable '{}', but it is not set",
+                environment, variable
+            ),
+            EnvironmentError::Secret {
+                environment,
+                source,
+                message,
+            } => write!(
+                f,
+                "The environment '{}' could not load the secret from the {}: {}",
+                environment, source, message
+            ),
+            EnvironmentError::Source {
+                environment,
+                message,
+            } => write!(
+                f,
+                "The environment '{}' could not load the environment source {}",
+                environment, message
+            ),
+            EnvironmentError::Schema {
+                environment,
+                violations,
+            } => {
+                write!(
+                    f,
+                    "The environment '{}' does not match its schema:",
+                    environment
+                )?;
+                for violation in violations {
+                    write!(f, "\n\t{}", violation)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvironmentError {}
+
+/// Overrides of an environment from the command line, with the highest precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvironmentOverrides {
+    /// Name of the environment to use instead of the one of the command
+    pub environment: Option<String>,
+    /// .env files to load, in order
+    pub env_files: Vec<PathBuf>,
+    /// Variables to set after the files, in order
+    pub values: Vec<(String, String)>,
+}
+
+impl EnvironmentOverrides {
+    /// Check if no variables are overridden, the `environment` is not considered.
+    pub fn is_empty(&self) -> bool {
+        self.env_files.is_empty() && self.values.is_empty()
+    }
+}
+
+/// The part of an environment a variable was loaded from, in the order of precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Layer {
+    #[serde(rename = "system")]
+    System,
+    #[serde(rename = "sources")]
+    Source,
+    #[serde(rename = "secret_sources")]
+    SecretSource,
+    #[serde(rename = "values")]
+    Value,
+    #[serde(rename = "prepend")]
+    Prepend,
+    #[serde(rename = "append")]
+    Append,
+    #[serde(rename = "command_line")]
+    CommandLine,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layer::System => write!(f, "system"),
+            Layer::Source => write!(f, "sources"),
+            Layer::SecretSource => write!(f, "secret_sources"),
+            Layer::Value => write!(f, "values"),
+            Layer::Prepend => write!(f, "prepend"),
+            Layer::Append => write!(f, "append"),
+            Layer::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Where the value of a variable of a resolved environment came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Provenance {
+    /// Name of the environment that set the value, a parent if the value was inherited
+    pub environment: String,
+    pub layer: Layer,
+    /// The system variable, file or secret provider the value was loaded from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// Where the value came from that was overridden by this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overrode: Option<Box<Provenance>>,
+}
+
+impl Provenance {
+    /// Append the provenance of an overridden value to the end of the chain.
+    fn overriding(mut self, previous: Option<Provenance>) -> Provenance {
+        self.overrode = match self.overrode.take() {
+            Some(overrode) => Some(Box::new(overrode.overriding(previous))),
+            None => previous.map(Box::new),
+        };
+        self
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of '{}'", self.layer, self.environment)?;
+        if let Some(origin) = &self.origin {
+            write!(f, " ({})", origin)?;
+        }
+        Ok(())
+    }
+}
+
+/// Separator of the entries of list variables like `PATH`, used by `prepend` and `append`
+#[cfg(windows)]
+pub const LIST_SEPARATOR: &str = ";";
+/// Separator of the entries of list variables like `PATH`, used by `prepend` and `append`
+#[cfg(not(windows))]
+pub const LIST_SEPARATOR: &str = ":";
+
+/// Names of variables whose values are always sensitive, see `is_sensitive`
+pub const SENSITIVE_NAME_PATTERNS: [&str; 4] = ["*_TOKEN", "*_PASSWORD", "*_SECRET", "*_API_KEY"];
+
+/// An entry of `Environment.system`.
+///
+/// Entries are written like `HOME`, `AWS_*`, `HOST_HOME=HOME`, `EDITOR:-vi` or `API_TOKEN!`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemVariable {
+    /// Name of the variable in the environment, the same as `source` if it is not renamed
+    pub name: String,
+    /// Name of the system variable, or a glob pattern like `AWS_*`
+    pub source: String,
+    /// Value to use when the system variable is not set or empty
+    pub default: Option<String>,
+    /// Fail when the system variable is not set, or no variable matches the pattern
+    pub required: bool,
+}
+
+impl SystemVariable {
+    /// Parse an entry of `Environment.system`.
+    pub fn parse(entry: &str) -> Result<SystemVariable, String> {
+        let (rest, default) = match entry.split_once(":-") {
+            Some((rest, default)) => (rest, Some(default.to_string())),
+            None => (entry, None),
+        };
+        let (rest, required) = match rest.strip_suffix('!') {
+            Some(rest) => (rest, true),
+            None => (rest, false),
+        };
+        let (name, source) = match rest.split_once('=') {
+            Some((name, source)) => (name.trim(), source.trim()),
+            None => (rest.trim(), rest.trim()),
+        };
+
+        if name.is_empty() || source.is_empty() {
+            return Err("the name of the variable is empty".to_string());
+        }
+        if required && default.is_some() {
+            return Err("a required variable can not have a default".to_string());
+        }
+        let variable = SystemVariable {
+            name: name.to_string(),
+            source: source.to_string(),
+            default,
+            required,
+        };
+        if variable.is_pattern() {
+            if name != source {
+                return Err("a pattern can not be renamed".to_string());
+            }
+            if variable.default.is_some() {
+                return Err("a pattern can not have a default".to_string());
+            }
+            glob::Pattern::new(source).map_err(|e| e.to_string())?;
+        }
+        Ok(variable)
+    }
+
+    /// Whether the source is a glob pattern that can match multiple variables.
+    pub fn is_pattern(&self) -> bool {
+        self.source.contains(['*', '?', '['])
+    }
+
+    /// Copy the matching variables of the system into the environment.
+    ///
+    /// Returns `false` if the variable is required but not set.
+    fn load(&self, out_env: &mut HashMap<String, String>) -> bool {
+        if self.is_pattern() {
+            let Ok(pattern) = glob::Pattern::new(&self.source) else {
+                return false;
+            };
+            let mut found = false;
+            for (key, value) in std::env::vars_os() {
+                if let (Some(key), Some(value)) = (key.to_str(), value.to_str()) {
+                    if pattern.matches(key) {
+                        out_env.insert(key.to_string(), value.to_string());
+                        found = true;
+                    }
+                }
+            }
+            return found || !self.required;
+        }
+
+        match std::env::var(&self.source) {
+            Ok(value) if !value.is_empty() || self.default.is_none() => {
+                out_env.insert(self.name.clone(), value);
+            }
+            _ => match &self.default {
+                Some(default) => {
+                    out_env.insert(self.name.clone(), default.clone());
+                }
+                None => return !self.required,
+            },
+        }
+        true
+    }
+}
 
 /// Try to find the environment by name from the environments.
 ///
-/// Also recursively resolve all sources and includes.
+/// Also recursively resolve all sources and inherited environments.
+/// Parents are loaded in the order of `inherits`, so the last parent has the highest precedence.
+/// Templates in the values are rendered after all of them are merged, see `template::render_environment`.
+/// The returned environment carries the isolation of the environment or its parents, see `resolve_isolation`,
+/// and lists the sensitive variables in `secret`, their values are marked with `log::mark_secret`.
+/// Afterwards the variables are validated against the `schema` of the environment and its parents,
+/// all violations are returned at once, see `validate_schema`.
+/// An empty name resolves to an empty environment.
 pub fn fetch_environment(
     environment: &str,
     environments: &Environments,
     workdir: &Path,
-) -> Option<Environment> {
-    if environment.is_empty() {
-        return None;
+) -> Result<Environment, EnvironmentError> {
+    fetch_environment_with_provenance(environment, environments, workdir).map(|(env, _)| env)
+}
+
+/// Like `fetch_environment`, but also return where the value of each variable came from.
+pub fn fetch_environment_with_provenance(
+    environment: &str,
+    environments: &Environments,
+    workdir: &Path,
+) -> Result<(Environment, BTreeMap<String, Provenance>), EnvironmentError> {
+    fetch_environment_with_overrides(
+        environment,
+        environments,
+        workdir,
+        &EnvironmentOverrides::default(),
+    )
+}
+
+/// Like `fetch_environment_with_provenance`, with the overrides layered on top of the environment.
+///
+/// The overrides are applied before the values are rendered and validated against the schema.
+/// With overrides, an empty name resolves to an environment with only the overrides.
+pub fn fetch_environment_with_overrides(
+    environment: &str,
+    environments: &Environments,
+    workdir: &Path,
+    overrides: &EnvironmentOverrides,
+) -> Result<(Environment, BTreeMap<String, Provenance>), EnvironmentError> {
+    fetch(environment, environments, workdir, overrides, None)
+}
+
+/// Like `fetch_environment`, but without running commands or decrypting files, for tools like the language server.
+///
+/// Secret providers and sources with `exec` are not run, they are returned with their description.
+/// Variables of named secret providers are set to `log::SECRET_PLACEHOLDER`.
+/// The schema is not validated, as the skipped variables are missing.
+pub fn fetch_static_environment(
+    environment: &str,
+    environments: &Environments,
+    workdir: &Path,
+) -> Result<(Environment, Vec<String>), EnvironmentError> {
+    let mut skipped: Vec<String> = Vec::new();
+    let (env, _) = fetch(
+        environment,
+        environments,
+        workdir,
+        &EnvironmentOverrides::default(),
+        Some(&mut skipped),
+    )?;
+    Ok((env, skipped))
+}
+
+/// Resolve, render and validate the environment, providers are skipped if `skipped` is given.
+fn fetch(
+    environment: &str,
+    environments: &Environments,
+    workdir: &Path,
+    overrides: &EnvironmentOverrides,
+    mut skipped: Option<&mut Vec<String>>,
+) -> Result<(Environment, BTreeMap<String, Provenance>), EnvironmentError> {
+    let is_static = skipped.is_some();
+    if environment.is_empty() && overrides.is_empty() {
+        return Ok((Environment::new_empty(), BTreeMap::new()));
     }
 
-    let mut found_env: Option<Environment> = None;
-    for env in environments {
-        if env.name == environment {
-            found_env = Some(env.clone());
+    let mut chain: Vec<String> = Vec::new();
+    let mut secret: Vec<String> = Vec::new();
+    let mut resolved = if environment.is_empty() {
+        Resolved::default()
+    } else {
+        resolve_values(
+            environment,
+            None,
+            environments,
+            workdir,
+            &mut chain,
+            &mut secret,
+            &mut skipped,
+        )?
+    };
+    let provenance = |origin: String| Provenance {
+        environment: environment.to_string(),
+        layer: Layer::CommandLine,
+        origin: Some(origin),
+        overrode: None,
+    };
+    for path in &overrides.env_files {
+        let source = EnvironmentSource::Path(path.to_string_lossy().to_string());
+        let loaded =
+            sources::load_source(&source, workdir, &resolved.values).map_err(|message| {
+                EnvironmentError::Source {
+                    environment: environment.to_string(),
+                    message,
+                }
+            })?;
+        for (origin, values) in loaded {
+            for (key, value) in values {
+                resolved.insert(key, value, provenance(origin.clone()));
+            }
         }
     }
-    // Create a new environments, without the current environment
-    let new_environments = environments
-        .iter()
-        .filter(|env| env.name != environment)
-        .cloned()
-        .collect::<Vec<Environment>>();
+    for (key, value) in &overrides.values {
+        resolved.insert(key.clone(), value.clone(), provenance("--set".to_string()));
+    }
 
-    if found_env.is_none() {
-        if !environment.is_empty() {
-            log::error(format!("Could not find environment: {}", environment));
+    let rendered_values = template::render_environment(resolved.parts)?;
+    let mut current = Environment::new("current", Vec::new(), rendered_values);
+    current.isolation = resolve_isolation(environment, environments);
+
+    let mut sensitive: Vec<String> = current
+        .values
+        .keys()
+        .filter(|key| is_sensitive(key, &secret))
+        .cloned()
+        .collect();
+    sensitive.sort();
+    for key in &sensitive {
+        let value = &current.values[key];
+        if value.is_empty() || value.chars().count() >= log::MIN_SECRET_LENGTH {
+            log::mark_secret(value);
+        } else if is_declared_secret(key, &secret) {
+            log::warn(format!(
+                "The secret '{}' is shorter than {} characters and can not be masked reliably",
+                key,
+                log::MIN_SECRET_LENGTH
+            ));
+            log::mark_secret(value);
         }
-        return None;
+    }
+    current.secret = sensitive;
+    if is_static {
+        return Ok((current, resolved.provenance.into_iter().collect()));
     }
 
-    let mut current_env: HashMap<String, String> = HashMap::new();
-    let env = found_env.unwrap();
+    let violations = validate_schema(
+        &current,
+        &resolve_schema(environment, environments),
+        workdir,
+    );
+    if !violations.is_empty() {
+        return Err(EnvironmentError::Schema {
+            environment: environment.to_string(),
+            violations,
+        });
+    }
+    Ok((current, resolved.provenance.into_iter().collect()))
+}
 
-    if !env.system.is_empty() {
-        for e in &env.system {
-            if let Ok(value) = std::env::var(e) {
-                current_env.insert(e.to_string(), value);
-            }
+/// The value of a variable for printing, sensitive values are masked with `***`.
+pub fn shown_value(environment: &Environment, key: &str) -> String {
+    if environment.secret.iter().any(|secret| secret == key) {
+        return log::SECRET_PLACEHOLDER.to_string();
+    }
+    log::redact(environment.values.get(key).cloned().unwrap_or_default())
+}
+
+/// Format the variables of a resolved environment and where they came from as a table.
+///
+/// Sensitive values are masked, see `shown_value`.
+pub fn provenance_table(
+    environment: &Environment,
+    provenance: &BTreeMap<String, Provenance>,
+) -> String {
+    let mut rows: Vec<[String; 4]> = vec![[
+        "NAME".to_string(),
+        "VALUE".to_string(),
+        "FROM".to_string(),
+        "OVERRODE".to_string(),
+    ]];
+    for (key, from) in provenance {
+        let mut overrode: Vec<String> = Vec::new();
+        let mut previous = from.overrode.as_deref();
+        while let Some(p) = previous {
+            overrode.push(p.to_string());
+            previous = p.overrode.as_deref();
         }
+        rows.push([
+            key.clone(),
+            shown_value(environment, key),
+            from.to_string(),
+            overrode.join(", "),
+        ]);
     }
 
-    if !env.inherits.is_empty() {
-        let mut parent_envs: Vec<Environment> = Vec::new();
-        for parent_env in &env.inherits {
-            if let Some(mut parent) = fetch_environment(parent_env, &new_environments, workdir) {
-                // Prevent an environment from depending on itself
-                if parent.name == environment {
-                    continue;
-                }
-                load_env_from_file(workdir, &parent.sources, &mut parent.values);
-                parent_envs.push(parent);
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join("  ");
+            line.trim_end().to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Format the variables of a resolved environment and where they came from as JSON.
+///
+/// Sensitive values are masked, see `shown_value`.
+pub fn provenance_json(
+    name: &str,
+    environment: &Environment,
+    provenance: &BTreeMap<String, Provenance>,
+) -> String {
+    let variables: Vec<serde_json::Value> = provenance
+        .iter()
+        .map(|(key, from)| {
+            serde_json::json!({
+                "name": key,
+                "value": shown_value(environment, key),
+                "secret": environment.secret.contains(key),
+                "from": from,
+            })
+        })
+        .collect();
+    let shown = serde_json::json!({
+        "environment": name,
+        "variables": variables,
+    });
+    serde_json::to_string_pretty(&shown).unwrap_or_default()
+}
+
+/// Check if the values of a variable must never be printed.
+///
+/// A variable is sensitive if its name matches one of the `secret` entries,
+/// which are names or glob patterns, or one of the `SENSITIVE_NAME_PATTERNS`.
+pub fn is_sensitive(key: &str, secret: &[String]) -> bool {
+    is_declared_secret(key, secret)
+        || SENSITIVE_NAME_PATTERNS
+            .iter()
+            .any(|pattern| matches_name(pattern, key))
+}
+
+/// Check if a variable is listed in `secret` or was loaded from a secret provider, unlike
+/// variables that are only sensitive by the `SENSITIVE_NAME_PATTERNS`.
+fn is_declared_secret(key: &str, secret: &[String]) -> bool {
+    secret.iter().any(|entry| matches_name(entry, key))
+}
+
+fn matches_name(entry: &str, key: &str) -> bool {
+    entry == key || glob::Pattern::new(entry).is_ok_and(|pattern| pattern.matches(key))
+}
+
+/// Resolve the isolation of an environment, the environments must be free of cycles.
+///
+/// An environment without an isolation uses the isolation of its parents, where the last parent wins.
+fn resolve_isolation(environment: &str, environments: &Environments) -> Option<Isolation> {
+    let env = environments
+        .iter()
+        .rev()
+        .find(|env| env.name == environment)?;
+    env.isolation.or_else(|| {
+        env.inherits
+            .iter()
+            .rev()
+            .find_map(|parent| resolve_isolation(parent, environments))
+    })
+}
+
+/// Resolve the schema of an environment, the environments must be free of cycles.
+///
+/// The rules of the parents are merged in the order of `inherits`, the rules of the environment itself win.
+fn resolve_schema(
+    environment: &str,
+    environments: &Environments,
+) -> BTreeMap<String, VariableSchema> {
+    let Some(env) = environments
+        .iter()
+        .rev()
+        .find(|env| env.name == environment)
+    else {
+        return BTreeMap::new();
+    };
+    let mut schema: BTreeMap<String, VariableSchema> = BTreeMap::new();
+    for parent in &env.inherits {
+        schema.extend(resolve_schema(parent, environments));
+    }
+    schema.extend(env.schema.clone());
+    schema
+}
+
+/// Check the variables of a resolved environment against a schema, and describe every violation.
+///
+/// Paths are relative to `workdir`. Sensitive values are masked in the messages, see `shown_value`.
+pub fn validate_schema(
+    environment: &Environment,
+    schema: &BTreeMap<String, VariableSchema>,
+    workdir: &Path,
+) -> Vec<String> {
+    let mut violations: Vec<String> = Vec::new();
+    for (key, rules) in schema {
+        let mut report = |message: String| match &rules.description {
+            Some(description) => violations.push(format!("{} ({})", message, description)),
+            None => violations.push(message),
+        };
+        let Some(value) = environment.values.get(key) else {
+            if rules.required {
+                report(format!("'{}' is required, but not set", key));
+            }
+            continue;
+        };
+        let shown = shown_value(environment, key);
+        if let Some(kind) = &rules.kind {
+            if let Err(expected) = check_type(kind, value, workdir) {
+                report(format!(
+                    "'{}' must be {}, but is '{}'",
+                    key, expected, shown
+                ));
             }
         }
-        parent_envs.reverse();
-        for parent_env in parent_envs {
-            for (key, value) in parent_env.values {
-                current_env.insert(key.clone(), value.clone());
+        if let Some(pattern) = &rules.pattern {
+            match regex::Regex::new(&format!("^(?:{})$", pattern)) {
+                Ok(regex) if regex.is_match(value) => {}
+                Ok(_) => report(format!(
+                    "'{}' must match '{}', but is '{}'",
+                    key, pattern, shown
+                )),
+                Err(e) => report(format!(
+                    "'{}' has the invalid pattern '{}': {}",
+                    key, pattern, e
+                )),
             }
         }
     }
+    violations
+}
+
+/// Check the value of a variable against a type, returns the description of the type if it does not match.
+fn check_type(kind: &VariableType, value: &str, workdir: &Path) -> Result<(), String> {
+    let matches = match kind {
+        VariableType::Int => value.parse::<i64>().is_ok(),
+        VariableType::Bool => ["true", "false", "1", "0", "yes", "no", "on", "off"]
+            .iter()
+            .any(|valid| valid.eq_ignore_ascii_case(value)),
+        VariableType::Url => url::Url::parse(value).is_ok(),
+        VariableType::Path => !value.is_empty() && workdir.join(value).exists(),
+        VariableType::Enum(values) => values.iter().any(|valid| valid == value),
+    };
+    if matches {
+        return Ok(());
+    }
+    Err(match kind {
+        VariableType::Int => "an int".to_string(),
+        VariableType::Bool => "a bool".to_string(),
+        VariableType::Url => "a URL".to_string(),
+        VariableType::Path => "an existing path".to_string(),
+        VariableType::Enum(values) => format!("one of '{}'", values.join("', '")),
+    })
+}
+
+/// The values of an environment while it is resolved, with the provenance of each value
+///
+/// Besides the plain text, each value keeps its parts, so only the templates from the project file
+/// are rendered and values from the system, sources and secret providers are used as they are.
+#[derive(Default)]
+struct Resolved {
+    values: HashMap<String, String>,
+    parts: HashMap<String, Vec<ValuePart>>,
+    provenance: HashMap<String, Provenance>,
+}
+
+impl Resolved {
+    /// Set a variable, the provenance of the previous value is kept as overridden.
+    ///
+    /// Only `values` are templates, the values of all other layers are literal.
+    fn insert(&mut self, key: String, value: String, provenance: Provenance) {
+        let part = match provenance.layer {
+            Layer::Value => ValuePart::Template(value),
+            _ => ValuePart::Literal(value),
+        };
+        self.insert_parts(key, vec![part], provenance);
+    }
 
-    load_env_from_file(workdir, &env.sources, &mut current_env);
-    for (key, value) in env.values {
-        current_env.insert(key.clone(), value.clone());
+    /// Set a variable from its parts, like the entries of `prepend` and the previous value.
+    fn insert_parts(&mut self, key: String, parts: Vec<ValuePart>, provenance: Provenance) {
+        let previous = self.provenance.remove(&key);
+        self.provenance
+            .insert(key.clone(), provenance.overriding(previous));
+        self.values
+            .insert(key.clone(), parts.iter().map(ValuePart::text).collect());
+        self.parts.insert(key, parts);
     }
 
-    let rendered_env = template::render_environment(current_env);
-    Some(Environment::new("current", Vec::new(), rendered_env))
+    /// Remove all variables whose name matches the name or glob pattern.
+    fn remove(&mut self, entry: &str) {
+        let pattern = glob::Pattern::new(entry).ok();
+        self.values.retain(|key, _| {
+            key != entry && !pattern.as_ref().is_some_and(|pattern| pattern.matches(key))
+        });
+        let values = &self.values;
+        self.parts.retain(|key, _| values.contains_key(key));
+        self.provenance.retain(|key, _| values.contains_key(key));
+    }
+
+    fn extend(&mut self, mut other: Resolved) {
+        for (key, parts) in other.parts {
+            if let Some(provenance) = other.provenance.remove(&key) {
+                self.insert_parts(key, parts, provenance);
+            }
+        }
+    }
+
+    /// The parts of a list variable that is not empty.
+    fn list_parts(&self, key: &str) -> Option<Vec<ValuePart>> {
+        self.values
+            .get(key)
+            .filter(|value| !value.is_empty())
+            .and_then(|_| self.parts.get(key).cloned())
+    }
 }
 
-fn load_env_from_file(
+/// Resolve the values of an environment and all its parents, without rendering them.
+///
+/// After the secret providers, the `unset` entries remove variables, then `values` are set
+/// and the entries of `prepend` and `append` are joined with the current values.
+///
+/// The `chain` contains the environments that are currently resolved, to detect cycles.
+/// The `secret` entries of all environments and the names of all variables from secret providers
+/// are added to `secret`.
+/// If `skipped` is given, secret providers and sources with `exec` are not run but added to it, see `fetch_static_environment`.
+fn resolve_values(
+    environment: &str,
+    inherited_by: Option<&str>,
+    environments: &Environments,
     workdir: &Path,
-    sources: &Vec<String>,
-    out_env: &mut HashMap<String, String>,
-) {
-    if !sources.is_empty() {
-        for path in sources {
-            let mut file_path = workdir.to_path_buf();
-            file_path.push(path);
-            if let Ok(data) = fs::read_to_string(file_path) {
-                if let Ok(btree) = dotenv_parser::parse_dotenv(data.as_str()) {
-                    for (k, v) in btree {
-                        out_env.insert(k, v);
-                    }
-                } else {
-                    log::error(format!("Could not parse environment file {}", path));
-                }
+    chain: &mut Vec<String>,
+    secret: &mut Vec<String>,
+    skipped: &mut Option<&mut Vec<String>>,
+) -> Result<Resolved, EnvironmentError> {
+    if chain.iter().any(|name| name == environment) {
+        let mut cycle = chain.clone();
+        cycle.push(environment.to_string());
+        return Err(EnvironmentError::Cycle { chain: cycle });
+    }
+
+    // the last environment with the name wins
+    let env = environments
+        .iter()
+        .rev()
+        .find(|env| env.name == environment)
+        .ok_or_else(|| EnvironmentError::NotFound {
+            name: environment.to_string(),
+            inherited_by: inherited_by.map(str::to_string),
+        })?;
+    let provenance = |layer: Layer, origin: Option<String>| Provenance {
+        environment: environment.to_string(),
+        layer,
+        origin,
+        overrode: None,
+    };
+
+    let mut current_env = Resolved::default();
+    for entry in &env.system {
+        let variable = SystemVariable::parse(entry).map_err(|message| {
+            EnvironmentError::InvalidSystemVariable {
+                environment: environment.to_string(),
+                entry: entry.clone(),
+                message,
+            }
+        })?;
+        let mut loaded: HashMap<String, String> = HashMap::new();
+        if !variable.load(&mut loaded) {
+            return Err(EnvironmentError::MissingSystemVariable {
+                environment: environment.to_string(),
+                variable: variable.source,
+            });
+        }
+        for (key, value) in loaded {
+            let origin = if variable.is_pattern() {
+                key.clone()
             } else {
-                log::error(format!("Could not read environment file: {}", path));
+                variable.source.clone()
+            };
+            current_env.insert(key, value, provenance(Layer::System, Some(origin)));
+        }
+    }
+
+    chain.push(environment.to_string());
+    for parent in &env.inherits {
+        let parent_values = resolve_values(
+            parent,
+            Some(environment),
+            environments,
+            workdir,
+            chain,
+            secret,
+            skipped,
+        )?;
+        current_env.extend(parent_values);
+    }
+    chain.pop();
+
+    for source in &env.sources {
+        if let (Some(skipped), EnvironmentSource::Typed(TypedSource { exec: Some(_), .. })) =
+            (skipped.as_deref_mut(), source)
+        {
+            skipped.push(source.describe());
+            continue;
+        }
+        let loaded =
+            sources::load_source(source, workdir, &current_env.values).map_err(|message| {
+                EnvironmentError::Source {
+                    environment: environment.to_string(),
+                    message,
+                }
+            })?;
+        for (origin, values) in loaded {
+            for (key, value) in values {
+                current_env.insert(key, value, provenance(Layer::Source, Some(origin.clone())));
+            }
+        }
+    }
+    for source in &env.secret_sources {
+        if let Some(skipped) = skipped.as_deref_mut() {
+            skipped.push(source.describe());
+            if let SecretSource::Command { name, .. } | SecretSource::File { name, .. } = source {
+                secret.push(name.clone());
+                current_env.insert(
+                    name.clone(),
+                    log::SECRET_PLACEHOLDER.to_string(),
+                    provenance(Layer::SecretSource, Some(source.describe())),
+                );
             }
+            continue;
+        }
+        let secrets =
+            secrets::load_secret(source, workdir).map_err(|message| EnvironmentError::Secret {
+                environment: environment.to_string(),
+                source: source.describe(),
+                message,
+            })?;
+        for (key, value) in secrets {
+            log::mark_secret(&value);
+            secret.push(key.clone());
+            current_env.insert(
+                key,
+                value,
+                provenance(Layer::SecretSource, Some(source.describe())),
+            );
         }
     }
+    secret.extend(env.secret.iter().cloned());
+    for entry in &env.unset {
+        current_env.remove(entry);
+    }
+    for (key, value) in &env.values {
+        current_env.insert(key.clone(), value.clone(), provenance(Layer::Value, None));
+    }
+    for (key, entries) in &env.prepend {
+        let mut lists = list_entries(entries, workdir);
+        lists.extend(current_env.list_parts(key));
+        current_env.insert_parts(
+            key.clone(),
+            join_lists(lists),
+            provenance(Layer::Prepend, None),
+        );
+    }
+    for (key, entries) in &env.append {
+        let mut lists: Vec<Vec<ValuePart>> = current_env.list_parts(key).into_iter().collect();
+        lists.extend(list_entries(entries, workdir));
+        current_env.insert_parts(
+            key.clone(),
+            join_lists(lists),
+            provenance(Layer::Append, None),
+        );
+    }
+
+    Ok(current_env)
+}
+
+/// The entries of `prepend` or `append` as templates, entries starting with `./` or `../` are resolved relative to `workdir`.
+fn list_entries(entries: &[String], workdir: &Path) -> Vec<Vec<ValuePart>> {
+    entries
+        .iter()
+        .map(|entry| {
+            let path = Path::new(entry);
+            match path.components().next() {
+                Some(Component::CurDir | Component::ParentDir) => workdir
+                    .join(path.strip_prefix(".").unwrap_or(path))
+                    .to_string_lossy()
+                    .to_string(),
+                _ => entry.clone(),
+            }
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| vec![ValuePart::Template(entry)])
+        .collect()
+}
+
+/// Join the parts of list entries with the `LIST_SEPARATOR`.
+fn join_lists(lists: Vec<Vec<ValuePart>>) -> Vec<ValuePart> {
+    let mut joined: Vec<ValuePart> = Vec::new();
+    for list in lists {
+        if !joined.is_empty() {
+            joined.push(ValuePart::Literal(LIST_SEPARATOR.to_string()));
+        }
+        joined.extend(list);
+    }
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(name: &str, inherits: &[&str], values: &[(&str, &str)]) -> Environment {
+        Environment::new(
+            name,
+            inherits.iter().map(|s| s.to_string()).collect(),
+            values
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn last_parent_has_precedence() {
+        let environments = vec![
+            env("a", &[], &[("X", "a"), ("A", "a")]),
+            env("b", &[], &[("X", "b")]),
+            env("child", &["a", "b"], &[]),
+        ];
+        let result = fetch_environment("child", &environments, Path::new(".")).unwrap();
+        assert_eq!(result.values.get("X"), Some(&"b".to_string()));
+        assert_eq!(result.values.get("A"), Some(&"a".to_string()));
+    }
+
+    #[test]
+    fn isolation_is_inherited_from_parents() {
+        let mut base = env("base", &[], &[]);
+        base.isolation = Some(Isolation::Minimal);
+        let mut strict = env("strict", &["base"], &[]);
+        strict.isolation = Some(Isolation::Clear);
+        let environments = vec![
+            base,
+            strict,
+            env("child", &["base"], &[]),
+            env("other", &[], &[]),
+        ];
+        let isolation_of = |name: &str| {
+            fetch_environment(name, &environments, Path::new("."))
+                .unwrap()
+                .isolation
+        };
+        assert_eq!(isolation_of("child"), Some(Isolation::Minimal));
+        assert_eq!(isolation_of("strict"), Some(Isolation::Clear));
+        assert_eq!(isolation_of("other"), None);
+    }
+
+    #[test]
+    fn sensitive_variables_are_listed() {
+        let mut base = env(
+            "base",
+            &[],
+            &[
+                ("CERT", "cert-2f9a7c1e"),
+                ("GITHUB_TOKEN", "ghp_3kF9xQ2mV7"),
+            ],
+        );
+        base.secret = vec!["CERT".to_string()];
+        let mut child = env(
+            "child",
+            &["base"],
+            &[("PRIVATE_KEY", "pk-8d41e07b"), ("USER", "alice")],
+        );
+        child.secret = vec!["*_KEY".to_string()];
+        let result = fetch_environment("child", &vec![base, child], Path::new(".")).unwrap();
+        assert_eq!(result.secret, vec!["CERT", "GITHUB_TOKEN", "PRIVATE_KEY"]);
+        assert!(!is_sensitive("USER", &result.secret));
+    }
+
+    #[test]
+    fn short_declared_secrets_are_masked() {
+        let mut dev = env("dev", &[], &[("PIN", "x9Q4"), ("CI_TOKEN", "zq7")]);
+        dev.secret = vec!["PIN".to_string()];
+        let result = fetch_environment("dev", &vec![dev], Path::new(".")).unwrap();
+        assert_eq!(result.secret, vec!["CI_TOKEN", "PIN"]);
+        assert_eq!(
+            log::redact("pin=x9Q4 user_x9Q4 zq7".to_string()),
+            "pin=*** user_*** zq7"
+        );
+        assert_eq!(shown_value(&result, "CI_TOKEN"), log::SECRET_PLACEHOLDER);
+    }
+
+    #[test]
+    fn static_environments_do_not_run_providers() {
+        let marker = std::env::temp_dir().join(format!("hisho-static-{}", std::process::id()));
+        let mut dev = env("dev", &[], &[("A", "a")]);
+        dev.sources = vec![EnvironmentSource::Typed(TypedSource {
+            exec: Some(crate::config_models::Process {
+                command: "touch".to_string(),
+                args: vec![marker.to_string_lossy().to_string()],
+                cwd: String::new(),
+            }),
+            ..TypedSource::default()
+        })];
+        dev.secret_sources = vec![SecretSource::Command {
+            name: "DB_PASSWORD".to_string(),
+            command: "touch".to_string(),
+            args: vec![marker.to_string_lossy().to_string()],
+        }];
+        let (result, skipped) =
+            fetch_static_environment("dev", &vec![dev], Path::new(".")).unwrap();
+        assert!(!marker.exists());
+        assert_eq!(
+            skipped,
+            vec!["command 'touch'", "command 'touch' for 'DB_PASSWORD'"]
+        );
+        assert_eq!(result.values.get("A"), Some(&"a".to_string()));
+        assert_eq!(
+            result.values.get("DB_PASSWORD"),
+            Some(&log::SECRET_PLACEHOLDER.to_string())
+        );
+        assert_eq!(result.secret, vec!["DB_PASSWORD"]);
+    }
+
+    #[test]
+    fn missing_sources_fail_unless_optional() {
+        let mut dev = env("dev", &[], &[("A", "a")]);
+        dev.sources = vec![EnvironmentSource::Path("missing.env".to_string())];
+        let error = fetch_environment("dev", &vec![dev.clone()], Path::new(".")).unwrap_err();
+        assert!(
+            matches!(&error, EnvironmentError::Source { environment, message }
+                if environment == "dev" && message.starts_with("'missing.env': "))
+        );
+        dev.sources = vec![EnvironmentSource::Typed(TypedSource {
+            path: Some("missing.env".to_string()),
+            optional: true,
+            ..TypedSource::default()
+        })];
+        let result = fetch_environment("dev", &vec![dev], Path::new(".")).unwrap();
+        assert_eq!(result.values.get("A"), Some(&"a".to_string()));
+    }
+
+    #[test]
+    fn provenance_lists_overridden_values() {
+        let environments = vec![
+            env("base", &[], &[("A", "base"), ("B", "base")]),
+            env(
+                "child",
+                &["base"],
+                &[("A", "child"), ("GH_TOKEN", "ghp_7Yt2Lw9cR4")],
+            ),
+        ];
+        let (result, provenance) =
+            fetch_environment_with_provenance("child", &environments, Path::new(".")).unwrap();
+
+        let a = &provenance["A"];
+        assert_eq!(a.to_string(), "values of 'child'");
+        assert_eq!(
+            a.overrode.as_deref().map(ToString::to_string),
+            Some("values of 'base'".to_string())
+        );
+        assert_eq!(provenance["B"].environment, "base");
+
+        let table = provenance_table(&result, &provenance);
+        assert!(table.starts_with("NAME      VALUE  FROM"), "{}", table);
+        assert!(
+            table.contains("GH_TOKEN  ***    values of 'child'"),
+            "{}",
+            table
+        );
+    }
+
+    #[test]
+    fn unset_and_list_composition() {
+        let base = env(
+            "base",
+            &[],
+            &[
+                ("PATH", "/usr/bin"),
+                ("AWS_KEY", "AKIA4EXAMPLE"),
+                ("AWS_REGION", "eu-west-1"),
+            ],
+        );
+        let mut child = env("child", &["base"], &[("KEEP", "yes")]);
+        child.unset = vec!["AWS_*".to_string()];
+        child.prepend =
+            HashMap::from([("PATH".to_string(), vec!["./node_modules/.bin".to_string()])]);
+        child.append = HashMap::from([
+            ("PATH".to_string(), vec!["/opt/bin".to_string()]),
+            ("PYTHONPATH".to_string(), vec!["lib".to_string()]),
+        ]);
+        let (result, provenance) =
+            fetch_environment_with_provenance("child", &vec![base, child], Path::new("/project"))
+                .unwrap();
+
+        assert!(!result.values.contains_key("AWS_KEY"));
+        assert!(!provenance.contains_key("AWS_REGION"));
+        assert_eq!(result.values["KEEP"], "yes");
+        let expected = ["/project/node_modules/.bin", "/usr/bin", "/opt/bin"].join(LIST_SEPARATOR);
+        assert_eq!(result.values["PATH"], expected);
+        assert_eq!(result.values["PYTHONPATH"], "lib");
+        assert_eq!(provenance["PATH"].to_string(), "append of 'child'");
+    }
+
+    #[test]
+    fn overrides_have_the_highest_precedence() {
+        let environments = vec![env("dev", &[], &[("A", "dev"), ("B", "dev")])];
+        let overrides = EnvironmentOverrides {
+            environment: None,
+            env_files: Vec::new(),
+            values: vec![("A".to_string(), "set".to_string())],
+        };
+        let (result, provenance) =
+            fetch_environment_with_overrides("dev", &environments, Path::new("."), &overrides)
+                .unwrap();
+        assert_eq!(result.values["A"], "set");
+        assert_eq!(result.values["B"], "dev");
+        assert_eq!(provenance["A"].to_string(), "command line of 'dev' (--set)");
+
+        let (result, _) =
+            fetch_environment_with_overrides("", &environments, Path::new("."), &overrides)
+                .unwrap();
+        assert_eq!(result.values.len(), 1);
+    }
+
+    #[test]
+    fn cycles_are_reported_with_the_full_path() {
+        let environments = vec![
+            env("root", &["a"], &[]),
+            env("a", &["b"], &[]),
+            env("b", &["a"], &[]),
+        ];
+        let err = fetch_environment("root", &environments, Path::new(".")).unwrap_err();
+        assert_eq!(
+            err,
+            EnvironmentError::Cycle {
+                chain: vec!["root", "a", "b", "a"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "The environment 'a' inherits from itself: root -> a -> b -> a"
+        );
+    }
+
+    #[test]
+    fn values_are_rendered_in_dependency_order() {
+        let environments = vec![env(
+            "dev",
+            &[],
+            &[("A", "{{env.B}}/x"), ("B", "{{env.C}}"), ("C", "c")],
+        )];
+        let result = fetch_environment("dev", &environments, Path::new(".")).unwrap();
+        assert_eq!(result.values.get("A"), Some(&"c/x".to_string()));
+
+        let environments = vec![env("dev", &[], &[("A", "{{env.B}}"), ("B", "{{env.A}}")])];
+        let err = fetch_environment("dev", &environments, Path::new(".")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The value of 'A' references itself: A -> B -> A"
+        );
+
+        let environments = vec![env("dev", &[], &[("A", "{{ env.MISSING }}")])];
+        let err = fetch_environment("dev", &environments, Path::new(".")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The value of 'A' references the unknown variable 'MISSING'"
+        );
+    }
+
+    #[test]
+    fn parse_system_variables() {
+        assert_eq!(
+            SystemVariable::parse("HOST_HOME=HOME"),
+            Ok(SystemVariable {
+                name: "HOST_HOME".to_string(),
+                source: "HOME".to_string(),
+                default: None,
+                required: false,
+            })
+        );
+        let variable = SystemVariable::parse("EDITOR:-vi").unwrap();
+        assert_eq!(variable.default, Some("vi".to_string()));
+        assert!(SystemVariable::parse("AWS_*!").unwrap().required);
+        assert!(SystemVariable::parse("MY_*=AWS_*").is_err());
+        assert!(SystemVariable::parse("TOKEN!:-x").is_err());
+    }
+
+    #[test]
+    fn only_project_values_are_templates() {
+        std::env::set_var("HISHO_TEST_LITERAL_PASSWORD", "pa{{ss}}{%wd");
+        let mut environment = env(
+            "dev",
+            &[],
+            &[("URL", "db://app:{{env.HISHO_TEST_LITERAL_PASSWORD}}@db")],
+        );
+        environment.system = vec!["HISHO_TEST_LITERAL_PASSWORD".to_string()];
+        environment.prepend = HashMap::from([(
+            "HISHO_TEST_LITERAL_PASSWORD".to_string(),
+            vec!["{{ 'bin' | upcase }}".to_string()],
+        )]);
+        let result = fetch_environment("dev", &vec![environment], Path::new(".")).unwrap();
+        assert_eq!(
+            result.values["HISHO_TEST_LITERAL_PASSWORD"],
+            format!("BIN{}pa{{{{ss}}}}{{%wd", LIST_SEPARATOR)
+        );
+        assert_eq!(
+            result.values["URL"],
+            format!("db://app:BIN{}pa{{{{ss}}}}{{%wd@db", LIST_SEPARATOR)
+        );
+    }
+
+    #[test]
+    fn system_variables_are_copied() {
+        std::env::set_var("HISHO_TEST_SYSTEM_A", "a");
+        std::env::set_var("HISHO_TEST_SYSTEM_B", "b");
+        let mut environment = env("dev", &[], &[]);
+        environment.system = vec![
+            "HISHO_TEST_SYSTEM_*".to_string(),
+            "RENAMED=HISHO_TEST_SYSTEM_A".to_string(),
+            "HISHO_TEST_UNSET:-fallback".to_string(),
+        ];
+        let result = fetch_environment("dev", &vec![environment.clone()], Path::new(".")).unwrap();
+        assert_eq!(
+            result.values.get("HISHO_TEST_SYSTEM_B"),
+            Some(&"b".to_string())
+        );
+        assert_eq!(result.values.get("RENAMED"), Some(&"a".to_string()));
+        assert_eq!(
+            result.values.get("HISHO_TEST_UNSET"),
+            Some(&"fallback".to_string())
+        );
+
+        environment.system = vec!["HISHO_TEST_UNSET!".to_string()];
+        let err = fetch_environment("dev", &vec![environment], Path::new(".")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The environment 'dev' requires the system variable 'HISHO_TEST_UNSET', but it is not set"
+        );
+    }
+
+    #[test]
+    fn unknown_parents_are_errors() {
+        let environments = vec![env("child", &["parnet"], &[])];
+        let err = fetch_environment("child", &environments, Path::new(".")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not find environment 'parnet', inherited by 'child'"
+        );
+        assert!(fetch_environment("", &environments, Path::new(".")).is_ok());
+    }
+
+    #[test]
+    fn all_schema_violations_are_reported() {
+        let mut base = env("base", &[], &[]);
+        base.schema.insert(
+            "DATABASE_URL".to_string(),
+            VariableSchema {
+                required: true,
+                description: Some("Connection string of the database".to_string()),
+                ..VariableSchema::default()
+            },
+        );
+        let mut dev = env(
+            "dev",
+            &["base"],
+            &[("PORT", "abc"), ("MODE", "prod"), ("NAME", "Web")],
+        );
+        dev.schema = HashMap::from([
+            (
+                "PORT".to_string(),
+                VariableSchema {
+                    kind: Some(VariableType::Int),
+                    ..VariableSchema::default()
+                },
+            ),
+            (
+                "MODE".to_string(),
+                VariableSchema {
+                    kind: Some(VariableType::Enum(vec![
+                        "dev".to_string(),
+                        "test".to_string(),
+                    ])),
+                    ..VariableSchema::default()
+                },
+            ),
+            (
+                "NAME".to_string(),
+                VariableSchema {
+                    pattern: Some("[a-z]+".to_string()),
+                    ..VariableSchema::default()
+                },
+            ),
+        ]);
+        let err = fetch_environment("dev", &vec![base, dev], Path::new(".")).unwrap_err();
+        assert_eq!(
+            err,
+            EnvironmentError::Schema {
+                environment: "dev".to_string(),
+                violations: vec![
+                    "'DATABASE_URL' is required, but not set (Connection string of the database)"
+                        .to_string(),
+                    "'MODE' must be one of 'dev', 'test', but is 'prod'".to_string(),
+                    "'NAME' must match '[a-z]+', but is 'Web'".to_string(),
+                    "'PORT' must be an int, but is 'abc'".to_string(),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn check_variable_types() {
+        let workdir = Path::new(".");
+        assert!(check_type(&VariableType::Int, "-8080", workdir).is_ok());
+        assert!(check_type(&VariableType::Bool, "Yes", workdir).is_ok());
+        assert!(check_type(&VariableType::Bool, "maybe", workdir).is_err());
+        assert!(check_type(&VariableType::Url, "postgres://localhost/app", workdir).is_ok());
+        assert!(check_type(&VariableType::Url, "/app", workdir).is_err());
+        assert!(check_type(&VariableType::Path, "src", workdir).is_ok());
+        assert!(check_type(&VariableType::Path, "missing", workdir).is_err());
+    }
 }
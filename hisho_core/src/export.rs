@@ -0,0 +1,142 @@
+// This file 'export.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config_models::{Environment, EnvironmentExport, Environments, ExportFormat};
+use crate::environment::fetch_environment;
+
+/// Format the variables of a resolved environment, sorted by name.
+///
+/// The values are written as-is, including secrets.
+/// Returns an error if a value can not be represented in the format.
+pub fn export_environment(
+    environment: &Environment,
+    format: ExportFormat,
+) -> Result<String, String> {
+    let variables: BTreeMap<&String, &String> = environment.values.iter().collect();
+    if format == ExportFormat::Json {
+        return serde_json::to_string_pretty(&variables)
+            .map(|json| json + "\n")
+            .map_err(|e| e.to_string());
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    for (key, value) in variables {
+        lines.push(match format {
+            ExportFormat::DotEnv => format!("{}=\"{}\"", key, escape_double_quoted(value)),
+            ExportFormat::Sh | ExportFormat::Fish => shell_statement(format, key, Some(value)),
+            ExportFormat::DockerEnv => {
+                if value.contains(['\n', '\r']) {
+                    return Err(format!(
+                        "The value of '{}' contains a line break, which docker env files do not support",
+                        key
+                    ));
+                }
+                format!("{}={}", key, value)
+            }
+            ExportFormat::Json => unreachable!(),
+        });
+    }
+    lines.push(String::new());
+    Ok(lines.join("\n"))
+}
+
+/// Format a statement that sets a variable in a shell, or unsets it if the `value` is `None`.
+///
+/// All formats other than `Fish` use the POSIX shell syntax.
+pub fn shell_statement(format: ExportFormat, key: &str, value: Option<&str>) -> String {
+    match (format, value) {
+        (ExportFormat::Fish, Some(value)) => format!(
+            "set -gx {} '{}'",
+            key,
+            value.replace('\\', "\\\\").replace('\'', "\\'")
+        ),
+        (ExportFormat::Fish, None) => format!("set -e {}", key),
+        (_, Some(value)) => format!("export {}='{}'", key, value.replace('\'', "'\\''")),
+        (_, None) => format!("unset {}", key),
+    }
+}
+
+/// Write an environment to the file of an export, relative to `workdir`.
+///
+/// The file is only written when its content changed, missing directories are created.
+pub fn write_export(
+    export: &EnvironmentExport,
+    environments: &Environments,
+    workdir: &Path,
+) -> Result<(), String> {
+    let environment =
+        fetch_environment(&export.environment, environments, workdir).map_err(|e| e.to_string())?;
+    let data = export_environment(&environment, export.format)?;
+
+    let path = workdir.join(&export.path);
+    if fs::read_to_string(&path).is_ok_and(|existing| existing == data) {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Could not create directory '{}': {}", dir.display(), e))?;
+    }
+    fs::write(&path, data).map_err(|e| format!("Could not write '{}': {}", export.path, e))
+}
+
+fn escape_double_quoted(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn environment() -> Environment {
+        let values = HashMap::from([
+            ("B".to_string(), "it's \"quoted\"".to_string()),
+            ("A".to_string(), "plain".to_string()),
+        ]);
+        Environment::new("current", Vec::new(), values)
+    }
+
+    #[test]
+    fn formats_quote_values() {
+        let env = environment();
+        assert_eq!(
+            export_environment(&env, ExportFormat::DotEnv).unwrap(),
+            "A=\"plain\"\nB=\"it's \\\"quoted\\\"\"\n"
+        );
+        assert_eq!(
+            export_environment(&env, ExportFormat::Sh).unwrap(),
+            "export A='plain'\nexport B='it'\\''s \"quoted\"'\n"
+        );
+        assert_eq!(
+            export_environment(&env, ExportFormat::Fish).unwrap(),
+            "set -gx A 'plain'\nset -gx B 'it\\'s \"quoted\"'\n"
+        );
+        assert_eq!(
+            export_environment(&env, ExportFormat::DockerEnv).unwrap(),
+            "A=plain\nB=it's \"quoted\"\n"
+        );
+    }
+
+    #[test]
+    fn docker_env_rejects_line_breaks() {
+        let env = Environment::new(
+            "current",
+            Vec::new(),
+            HashMap::from([("CERT".to_string(), "a\nb".to_string())]),
+        );
+        assert!(export_environment(&env, ExportFormat::DockerEnv).is_err());
+    }
+}
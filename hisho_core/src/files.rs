@@ -24,7 +24,7 @@ pub fn resolve_path(path: String) -> Result<PathBuf, io::Error> {
     } else {
         Err(io::Error::new(
             io::ErrorKind::NotFound,
-            "Could not find service file",
+            "Could not find file",
         ))
     }
 }
@@ -48,6 +48,6 @@ pub fn get_home_dir() -> Option<String> {
     None
 }
 
-pub fn string_to_path(path: &String) -> PathBuf {
-    PathBuf::from(path.clone())
+pub fn string_to_path(path: &str) -> PathBuf {
+    PathBuf::from(path)
 }
@@ -8,7 +8,7 @@
 
 use gix::date::time::format::ISO8601_STRICT;
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// Fetch git repository metadata for a given path
 ///
@@ -92,3 +92,12 @@ pub fn fetch_repo_vars(dir: &Path) -> HashMap<String, String> {
     }
     result
 }
+
+/// Find the root directory of the git repository that contains the given path
+///
+/// Returns `None` if the path is not inside a git repository or the repository is bare.
+pub fn find_repo_root(dir: &Path) -> Option<PathBuf> {
+    let repo = gix::discover(dir).ok()?;
+    let work_dir = repo.work_dir()?;
+    std::fs::canonicalize(work_dir).ok()
+}
@@ -0,0 +1,389 @@
+// This file 'hook.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Load environments into interactive shells when changing into a project directory.
+//!
+//! The shell hook runs `hisho env diff-export` before every prompt, which prints the statements
+//! to get from the variables set before to the variables of the current project and environment.
+//! The state is kept in the `HISHO_DIFF` variable of the shell, nothing is written to disk.
+//! Only projects that were allowed with `hisho allow` are loaded, see the `trust` module.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config_models::{ExportFormat, Project, SecretSource};
+use crate::environment::{fetch_environment, EnvironmentError};
+use crate::export::shell_statement;
+use crate::project::ProjectLoader;
+use crate::selection::select_environment;
+use crate::sources;
+use crate::state;
+use crate::{git, log, trust};
+
+/// Name of the shell variable that keeps the state of the hook
+pub const DIFF_VARIABLE: &str = "HISHO_DIFF";
+/// Name of the variable that selects the environment the hook loads
+pub const ENVIRONMENT_VARIABLE: &str = "HISHO_ENV";
+/// The environment the hook loads if neither `HISHO_ENV`, a default environment nor an environment rule of the project chose one
+pub const DEFAULT_ENVIRONMENT: &str = "dev";
+
+/// A shell the hook can be installed into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl HookShell {
+    pub const ALL: [HookShell; 3] = [HookShell::Bash, HookShell::Zsh, HookShell::Fish];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookShell::Bash => "bash",
+            HookShell::Zsh => "zsh",
+            HookShell::Fish => "fish",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HookShell> {
+        HookShell::ALL
+            .into_iter()
+            .find(|shell| shell.name() == name)
+    }
+
+    fn statement_format(&self) -> ExportFormat {
+        match self {
+            HookShell::Bash | HookShell::Zsh => ExportFormat::Sh,
+            HookShell::Fish => ExportFormat::Fish,
+        }
+    }
+}
+
+/// The snippet that installs the hook, to be evaluated in the startup file of the shell.
+///
+/// The `executable` is the path of hisho, like `/usr/local/bin/hisho`.
+pub fn hook_snippet(shell: HookShell, executable: &str) -> String {
+    let executable = shell_statement(shell.statement_format(), "X", Some(executable))
+        .split_once('\'')
+        .map(|(_, quoted)| format!("'{}", quoted))
+        .unwrap_or_default();
+    match shell {
+        HookShell::Bash => format!(
+            r#"_hisho_hook() {{
+  local previous_exit_status=$?
+  eval "$({} env diff-export --shell bash)"
+  return $previous_exit_status
+}}
+if [[ ";${{PROMPT_COMMAND[*]:-}};" != *";_hisho_hook;"* ]]; then
+  PROMPT_COMMAND="_hisho_hook${{PROMPT_COMMAND:+;$PROMPT_COMMAND}}"
+fi
+"#,
+            executable
+        ),
+        HookShell::Zsh => format!(
+            r#"_hisho_hook() {{
+  eval "$({} env diff-export --shell zsh)"
+}}
+typeset -ag precmd_functions
+if (( ! ${{precmd_functions[(I)_hisho_hook]}} )); then
+  precmd_functions=(_hisho_hook $precmd_functions)
+fi
+typeset -ag chpwd_functions
+if (( ! ${{chpwd_functions[(I)_hisho_hook]}} )); then
+  chpwd_functions=(_hisho_hook $chpwd_functions)
+fi
+"#,
+            executable
+        ),
+        HookShell::Fish => format!(
+            r#"function __hisho_hook --on-event fish_prompt --on-variable PWD
+    {} env diff-export --shell fish | source
+end
+"#,
+            executable
+        ),
+    }
+}
+
+/// The state of the hook, kept as JSON in the `HISHO_DIFF` variable of the shell.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffState {
+    /// Path of the loaded project file
+    pub project: String,
+    /// Name of the loaded environment
+    pub environment: String,
+    /// Name of the environment requested with `HISHO_ENV`, `None` if it was chosen by the project
+    #[serde(default)]
+    pub requested: Option<String>,
+    /// Files the environment was loaded from, with their modification time in nanoseconds
+    pub watched: BTreeMap<String, u64>,
+    /// Variables set by the hook, with their values before, `None` if they were not set
+    pub original: BTreeMap<String, Option<String>>,
+}
+
+impl DiffState {
+    /// Check if none of the watched files changed since the environment was loaded.
+    fn is_fresh(&self, project_file: &str, requested: Option<&str>) -> bool {
+        self.project == project_file
+            && self.requested.as_deref() == requested
+            && self
+                .watched
+                .iter()
+                .all(|(path, mtime)| modified_nanos(Path::new(path)) == *mtime)
+    }
+}
+
+/// Print the statements to get the shell from the previous state to the environment of the project in `dir`.
+///
+/// If the project files and the `.env` files did not change since the previous call, nothing is printed.
+/// Without an `environment`, the environment is chosen by the project, see `selection::select_environment`,
+/// and `DEFAULT_ENVIRONMENT` is loaded if the project chose none.
+/// The state file of the project and the git `HEAD` are watched, so `hisho env use` and changing branches are picked up.
+/// Without a project or with an empty `environment`, the variables of the previous state are restored.
+/// A project that is not allowed, see `trust::is_allowed`, is not loaded at all, the previous state is restored and a warning is printed once.
+/// Errors are reported and keep the previous state, so a broken project file does not unload the environment.
+pub fn diff_export(
+    shell: HookShell,
+    dir: &Path,
+    environment: Option<&str>,
+    previous: Option<&str>,
+) -> String {
+    let format = shell.statement_format();
+    let previous: Option<DiffState> = previous.and_then(|data| serde_json::from_str(data).ok());
+    let project_file = ProjectLoader::discover(dir)
+        .ok()
+        .and_then(|loader| fs::canonicalize(loader.path()).ok());
+    let project_file = project_file
+        .filter(|_| environment != Some(""))
+        .map(|path| path.to_string_lossy().to_string());
+
+    let next = match project_file {
+        Some(project_file) => {
+            if previous
+                .as_ref()
+                .is_some_and(|state| state.is_fresh(&project_file, environment))
+            {
+                return String::new();
+            }
+            match load_state(&project_file, environment) {
+                Ok(next) => next,
+                Err(Some(message)) => {
+                    log::error(message);
+                    return String::new();
+                }
+                Err(None) => None,
+            }
+        }
+        None => None,
+    };
+
+    let original = previous.map(|state| state.original).unwrap_or_default();
+    let mut statements: Vec<String> = Vec::new();
+    let Some((mut state, values)) = next else {
+        if original.is_empty() {
+            return String::new();
+        }
+        for (key, value) in &original {
+            statements.push(shell_statement(format, key, value.as_deref()));
+        }
+        statements.push(shell_statement(format, DIFF_VARIABLE, None));
+        return statements.join("\n") + "\n";
+    };
+
+    // restore the variables that are not part of the new environment
+    for (key, value) in &original {
+        if !values.contains_key(key) {
+            statements.push(shell_statement(format, key, value.as_deref()));
+        }
+    }
+    for (key, value) in &values {
+        let current = std::env::var(key).ok();
+        let before = match original.get(key) {
+            Some(before) => before.clone(),
+            None => current.clone(),
+        };
+        state.original.insert(key.clone(), before);
+        if current.as_deref() != Some(value.as_str()) {
+            statements.push(shell_statement(format, key, Some(value)));
+        }
+    }
+    let data = serde_json::to_string(&state).unwrap_or_default();
+    statements.push(shell_statement(format, DIFF_VARIABLE, Some(&data)));
+    statements.join("\n") + "\n"
+}
+
+/// Load the project and the `requested` or chosen environment, returns `None` if the environment does not exist in the project.
+///
+/// Errors are returned as a message.
+#[allow(clippy::type_complexity)]
+fn load_state(
+    project_file: &str,
+    requested: Option<&str>,
+) -> Result<Option<(DiffState, BTreeMap<String, String>)>, Option<String>> {
+    // environments can run commands, nothing of a project is resolved before it was allowed
+    if !trust::is_allowed(Path::new(project_file)) {
+        log::warn(format!(
+            "The project file '{}' is not allowed to be loaded automatically, run 'hisho allow' to allow it",
+            project_file
+        ));
+        let watched = trust::trusted_files(Path::new(project_file))
+            .unwrap_or_else(|_| vec![Path::new(project_file).to_path_buf()])
+            .into_iter()
+            .chain(trust::allowed_file())
+            .map(|path| (path.to_string_lossy().to_string(), modified_nanos(&path)))
+            .collect();
+        let state = DiffState {
+            project: project_file.to_string(),
+            environment: String::new(),
+            requested: requested.map(str::to_string),
+            watched,
+            original: BTreeMap::new(),
+        };
+        return Ok(Some((state, BTreeMap::new())));
+    }
+
+    let (project, origins) = ProjectLoader::new(project_file)
+        .load_with_origins()
+        .map_err(|e| Some(e.to_string()))?;
+    let workdir = Path::new(&project.workdir);
+    let environment = match requested {
+        Some(environment) => environment.to_string(),
+        None => Some(select_environment(&project, None, "").environment)
+            .filter(|environment| !environment.is_empty())
+            .unwrap_or_else(|| DEFAULT_ENVIRONMENT.to_string()),
+    };
+    let environment = environment.as_str();
+    let env = match fetch_environment(environment, &project.environments, workdir) {
+        Ok(env) => env,
+        Err(EnvironmentError::NotFound {
+            inherited_by: None, ..
+        }) => return Err(None),
+        Err(e) => return Err(Some(e.to_string())),
+    };
+
+    let mut watched: BTreeMap<String, u64> = BTreeMap::new();
+    let files = std::iter::once(Path::new(&project.file).to_path_buf())
+        .chain(origins.values().flatten().map(|path| workdir.join(path)))
+        .chain(environment_files(&project, environment))
+        .chain(trust::trusted_files(Path::new(project_file)).unwrap_or_default())
+        .chain(trust::allowed_file())
+        .chain(
+            selection_files(workdir)
+                .into_iter()
+                .filter(|_| requested.is_none()),
+        );
+    for path in files {
+        let mtime = modified_nanos(&path);
+        watched.insert(path.to_string_lossy().to_string(), mtime);
+    }
+
+    let state = DiffState {
+        project: project_file.to_string(),
+        environment: environment.to_string(),
+        requested: requested.map(str::to_string),
+        watched,
+        original: BTreeMap::new(),
+    };
+    Ok(Some((state, env.values.into_iter().collect())))
+}
+
+/// The `.env` files and secret files of an environment and all its parents.
+fn environment_files(project: &Project, environment: &str) -> Vec<std::path::PathBuf> {
+    let workdir = Path::new(&project.workdir);
+    let mut files = Vec::new();
+    let mut visited: HashSet<&str> = HashSet::new();
+    let mut pending: Vec<&str> = vec![environment];
+    while let Some(name) = pending.pop() {
+        if !visited.insert(name) {
+            continue;
+        }
+        for env in project.environments.iter().filter(|env| env.name == name) {
+            for source in &env.sources {
+                files.extend(sources::source_files(source, workdir));
+            }
+            for source in &env.secret_sources {
+                match source {
+                    SecretSource::AgeFile { path, .. } | SecretSource::File { path, .. } => {
+                        files.push(workdir.join(path))
+                    }
+                    SecretSource::Command { .. } => {}
+                }
+            }
+            pending.extend(env.inherits.iter().map(String::as_str));
+        }
+    }
+    files
+}
+
+/// The files the project chooses its environment from, the state file and the `HEAD` of the git repository.
+fn selection_files(workdir: &Path) -> Vec<std::path::PathBuf> {
+    let mut files = vec![state::state_file(workdir)];
+    if let Some(root) = git::find_repo_root(workdir) {
+        files.push(root.join(".git").join("HEAD"));
+    }
+    files
+}
+
+/// The modification time of a file in nanoseconds, or 0 if it does not exist.
+fn modified_nanos(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_previous_values_without_project() {
+        let previous = DiffState {
+            project: "/nowhere/hisho.ron".to_string(),
+            environment: "dev".to_string(),
+            requested: None,
+            watched: BTreeMap::new(),
+            original: BTreeMap::from([
+                ("HISHO_TEST_A".to_string(), Some("before".to_string())),
+                ("HISHO_TEST_B".to_string(), None),
+            ]),
+        };
+        let data = serde_json::to_string(&previous).unwrap();
+        let statements = diff_export(HookShell::Bash, Path::new("/"), Some(""), Some(&data));
+        assert_eq!(
+            statements,
+            "export HISHO_TEST_A='before'\nunset HISHO_TEST_B\nunset HISHO_DIFF\n"
+        );
+        assert_eq!(
+            diff_export(HookShell::Fish, Path::new("/"), Some(""), None),
+            String::new()
+        );
+    }
+
+    #[test]
+    fn snippets_call_diff_export() {
+        for shell in HookShell::ALL {
+            let snippet = hook_snippet(shell, "/usr/bin/hi sho");
+            assert!(
+                snippet.contains(&format!(
+                    "'/usr/bin/hi sho' env diff-export --shell {}",
+                    shell.name()
+                )),
+                "{}",
+                snippet
+            );
+        }
+    }
+}
@@ -8,13 +8,25 @@
 
 pub mod arg_parse;
 pub mod build_tool;
+pub mod check;
 pub mod command;
 pub mod config_models;
 pub mod containers;
 pub mod environment;
+pub mod export;
 pub mod files;
 pub mod git;
+pub mod hook;
 pub mod log;
+pub mod migrate;
+pub mod overrides;
+pub mod project;
+pub mod schema;
+pub mod secrets;
+pub mod selection;
 pub mod service;
 pub mod shell;
+pub mod sources;
+pub mod state;
 pub mod template;
+pub mod trust;
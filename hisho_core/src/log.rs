@@ -6,18 +6,81 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::sync::Mutex;
+
 use color_print::cformat;
 
+/// Values that are replaced in all printed messages
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Placeholder that is printed instead of a secret value
+pub const SECRET_PLACEHOLDER: &str = "***";
+/// Secrets shorter than this can not be masked reliably, they also match parts of unrelated text
+pub const MIN_SECRET_LENGTH: usize = 6;
+
 /// Print to stdout with a colored prefix
 pub fn print(text: String) {
-    println!("{} {}", get_tag(), text);
+    println!("{} {}", get_tag(), redact(text));
+}
+
+/// Print in yellow color to stderr with a colored prefix
+pub fn warn(text: String) {
+    eprintln!("{}", cformat!("{} <yellow>{}</>", get_tag(), redact(text)));
 }
 
 /// Print in red color to stderr with a colored prefix
 pub fn error(text: String) {
-    eprintln!("{}", cformat!("{} <red>{}</>", get_tag(), text));
+    eprintln!("{}", cformat!("{} <red>{}</>", get_tag(), redact(text)));
+}
+
+/// Mark a value as secret, every occurrence of it is replaced with `***` in all following messages.
+///
+/// Empty values are ignored.
+pub fn mark_secret(value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+    if !secrets.iter().any(|secret| secret == value) {
+        secrets.push(value.to_string());
+        // replace longer secrets first, so no part of them is left when they contain shorter ones
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    }
+}
+
+/// Forget all values marked as secret.
+pub fn clear_secrets() {
+    SECRETS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+/// Replace all secret values in the text with `***`, also where they are part of longer words.
+pub fn redact(text: String) -> String {
+    let secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+    secrets.iter().fold(text, |text, secret| {
+        text.replace(secret.as_str(), SECRET_PLACEHOLDER)
+    })
 }
 
 fn get_tag() -> String {
     cformat!("<green>{}</><cyan>{}</><green>{}</> ", "[", "Hisho", "]")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_redacted_everywhere() {
+        mark_secret("");
+        mark_secret("pw1");
+        mark_secret("hunter22-log-test");
+        assert_eq!(
+            redact("postgres://app:hunter22-log-test@db token_hunter22-log-testx".to_string()),
+            "postgres://app:***@db token_***x".to_string()
+        );
+        assert_eq!(
+            redact("login pw1pw1".to_string()),
+            "login ******".to_string()
+        );
+    }
+}
@@ -0,0 +1,417 @@
+// This file 'migrate.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config_models::Project;
+use crate::project::{parse_as, parse_project_as, serialize_project, ProjectError, ProjectFormat};
+
+/// The version of the project file format this version of Hisho writes and understands
+pub const CURRENT_VERSION: u32 = 2;
+
+/// The result of migrating the text of a project file.
+#[derive(Debug)]
+pub struct Migrated {
+    /// Version of the file before the migration
+    pub from: u32,
+    /// Version of the file after the migration
+    pub to: u32,
+    /// Human readable list of the changes made to the project
+    pub changes: Vec<String>,
+    /// The migrated text of the project file
+    pub data: String,
+    /// Whether the original text, including comments, was kept and only the version was changed
+    pub comments_kept: bool,
+}
+
+/// The original text of the file that is migrated.
+///
+/// Migrations use it to read fields that no longer exist in the `config_models`.
+struct Source<'a> {
+    format: ProjectFormat,
+    path: &'a Path,
+    data: &'a str,
+}
+
+/// A step that upgrades a project from the version `from` to the next version.
+struct Migration {
+    from: u32,
+    /// Apply the step to the project and return the changes made to it
+    apply: fn(&mut Project, &Source) -> Result<Vec<String>, ProjectError>,
+}
+
+/// All migrations, in order of their version
+const MIGRATIONS: [Migration; 1] = [Migration {
+    from: 1,
+    apply: migrate_legacy_commands,
+}];
+
+/// Migrate the text of a project file to the `CURRENT_VERSION`, step by step.
+///
+/// When the migrations only changed the version, the version is edited in the original text
+/// to keep comments and formatting. Otherwise the migrated project is serialized again.
+pub fn migrate_source(
+    format: ProjectFormat,
+    path: &Path,
+    data: &str,
+) -> Result<Migrated, ProjectError> {
+    let mut project = parse_project_as(format, path, data)?;
+    let from = project.version;
+    if from > CURRENT_VERSION {
+        return Err(ProjectError::UnsupportedVersion {
+            path: path.to_path_buf(),
+            version: from,
+        });
+    }
+    if from == CURRENT_VERSION {
+        return Ok(Migrated {
+            from,
+            to: from,
+            changes: Vec::new(),
+            data: data.to_string(),
+            comments_kept: true,
+        });
+    }
+
+    let source = Source { format, path, data };
+    let mut changes: Vec<String> = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= from) {
+        changes.extend((migration.apply)(&mut project, &source)?);
+    }
+    project.version = CURRENT_VERSION;
+
+    if changes.is_empty() {
+        if let Some(edited) = set_version(format, data, CURRENT_VERSION) {
+            // only keep the edited text when it still parses to the new version
+            if let Ok(edited_project) = parse_project_as(format, path, &edited) {
+                if edited_project.version == CURRENT_VERSION {
+                    return Ok(Migrated {
+                        from,
+                        to: CURRENT_VERSION,
+                        changes,
+                        data: edited,
+                        comments_kept: true,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(Migrated {
+        from,
+        to: CURRENT_VERSION,
+        changes,
+        data: serialize_project(&project, format)?,
+        comments_kept: false,
+    })
+}
+
+/// Migrate a single project file in place, without resolving its includes.
+///
+/// The file is only written when its version changed.
+pub fn migrate_project_file(path: &Path, dry_run: bool) -> Result<Migrated, ProjectError> {
+    let format = ProjectFormat::for_project_file(path);
+    let data = fs::read_to_string(path).map_err(|e| ProjectError::Read {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    let migrated = migrate_source(format, path, &data)?;
+    if !dry_run && migrated.from != migrated.to {
+        fs::write(path, &migrated.data).map_err(|e| ProjectError::Write {
+            path: PathBuf::from(path),
+            source: e,
+        })?;
+    }
+    Ok(migrated)
+}
+
+/// A `Project` of version 1, with the fields of `Command` that have been removed since.
+#[derive(Deserialize)]
+#[serde(rename = "Project")]
+struct LegacyProject {
+    #[serde(default)]
+    commands: Vec<LegacyCommand>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Command")]
+struct LegacyCommand {
+    name: String,
+    #[serde(default)]
+    capture_all: Option<bool>,
+    #[serde(default)]
+    args: HashMap<String, String>,
+}
+
+/// Version 1 to 2: `capture_all` has been replaced with `[[argv]]`, `args` has no effect.
+fn migrate_legacy_commands(
+    project: &mut Project,
+    source: &Source,
+) -> Result<Vec<String>, ProjectError> {
+    let legacy = parse_as::<LegacyProject>(source.format, source.path, source.data)?;
+    let mut changes: Vec<String> = Vec::new();
+    for legacy_cmd in legacy.commands {
+        let Some(cmd) = project
+            .commands
+            .iter_mut()
+            .find(|c| c.name == legacy_cmd.name)
+        else {
+            continue;
+        };
+        match legacy_cmd.capture_all {
+            Some(true) => {
+                for process in cmd.shell.iter_mut() {
+                    if !process.args.iter().any(|arg| arg == "[[argv]]") {
+                        process.args.push("[[argv]]".to_string());
+                    }
+                }
+                changes.push(format!(
+                    "command '{}': replaced 'capture_all' with '[[argv]]' in its processes",
+                    cmd.name
+                ));
+            }
+            Some(false) => {
+                changes.push(format!("command '{}': removed 'capture_all'", cmd.name));
+            }
+            None => {}
+        }
+        if !legacy_cmd.args.is_empty() {
+            #[allow(deprecated)]
+            cmd.args.clear();
+            changes.push(format!(
+                "command '{}': removed the deprecated 'args'",
+                cmd.name
+            ));
+        }
+    }
+    Ok(changes)
+}
+
+/// Set the top-level `version` field in the text of a project file.
+///
+/// Returns `None` when the position of the field could not be determined.
+fn set_version(format: ProjectFormat, data: &str, version: u32) -> Option<String> {
+    match format {
+        ProjectFormat::Ron => set_version_in_struct(data, "version", version),
+        ProjectFormat::Json => set_version_in_struct(data, "\"version\"", version),
+        ProjectFormat::Toml => set_version_in_lines(data, "=", version),
+        ProjectFormat::Yaml => set_version_in_lines(data, ":", version),
+    }
+}
+
+/// Set the version in the outermost struct of RON or object of JSON.
+fn set_version_in_struct(data: &str, key: &str, version: u32) -> Option<String> {
+    let bytes = data.as_bytes();
+    let mut depth = 0;
+    let mut opening: Option<usize> = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            // comments and RON attributes like `#![enable(implicit_some)]`
+            b'#' if depth == 0 => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i + 1 < bytes.len() && !(bytes[i] == b'*' && bytes[i + 1] == b'/') {
+                    i += 1;
+                }
+                i += 2;
+                continue;
+            }
+            b'"' => {
+                if depth == 1 && data[i..].starts_with(key) {
+                    if let Some(range) = value_after_key(data, i + key.len()) {
+                        return Some(format!(
+                            "{}{}{}",
+                            &data[..range.0],
+                            version,
+                            &data[range.1..]
+                        ));
+                    }
+                }
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            b'(' | b'[' | b'{' => {
+                depth += 1;
+                if depth == 1 && opening.is_none() {
+                    opening = Some(i);
+                }
+            }
+            b')' | b']' | b'}' => depth -= 1,
+            _ if depth == 1 && data[i..].starts_with(key) => {
+                let before = if i == 0 { b' ' } else { bytes[i - 1] };
+                if !before.is_ascii_alphanumeric() && before != b'_' {
+                    if let Some(range) = value_after_key(data, i + key.len()) {
+                        return Some(format!(
+                            "{}{}{}",
+                            &data[..range.0],
+                            version,
+                            &data[range.1..]
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    // no version yet, insert it as the first field
+    let opening = opening? + 1;
+    let rest = &data[opening..];
+    let line_end = rest.find('\n').unwrap_or(rest.len());
+    let field = format!("{}: {},", key, version);
+    if rest[..line_end].trim().is_empty() && line_end < rest.len() {
+        let indent: String = rest[line_end + 1..]
+            .chars()
+            .take_while(|c| *c == ' ' || *c == '\t')
+            .collect();
+        Some(format!("{}\n{}{}{}", &data[..opening], indent, field, rest))
+    } else {
+        Some(format!("{}{} {}", &data[..opening], field, rest))
+    }
+}
+
+/// Find the byte range of the number after a key and its separator `:`.
+fn value_after_key(data: &str, key_end: usize) -> Option<(usize, usize)> {
+    let rest = &data[key_end..];
+    let separator = rest.len() - rest.trim_start().len();
+    if !rest[separator..].starts_with(':') {
+        return None;
+    }
+    let after = &rest[separator + 1..];
+    let start = key_end + separator + 1 + (after.len() - after.trim_start().len());
+    let digits = data[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    if digits == 0 {
+        return None;
+    }
+    Some((start, start + digits))
+}
+
+/// Set the version in the top-level keys of TOML or block-style YAML.
+fn set_version_in_lines(data: &str, separator: &str, version: u32) -> Option<String> {
+    let mut lines: Vec<String> = data.lines().map(|line| line.to_string()).collect();
+    let field = match separator {
+        "=" => format!("version = {}", version),
+        _ => format!("version: {}", version),
+    };
+    let is_key = |line: &str, key: &str| {
+        line.strip_prefix(key)
+            .map(|rest| rest.trim_start().starts_with(separator))
+            .unwrap_or(false)
+    };
+
+    let mut name_line: Option<usize> = None;
+    for (index, line) in lines.iter_mut().enumerate() {
+        // TOML tables start after the top-level keys
+        if separator == "=" && line.trim_start().starts_with('[') {
+            break;
+        }
+        if is_key(line, "version") {
+            *line = field;
+            return Some(lines.join("\n") + "\n");
+        }
+        if name_line.is_none() && is_key(line, "name") {
+            name_line = Some(index);
+        }
+    }
+    lines.insert(name_line.map(|index| index + 1).unwrap_or(0), field);
+    Some(lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrate(format: ProjectFormat, data: &str) -> Migrated {
+        let path = format!("hisho.{}", format.extension());
+        migrate_source(format, Path::new(&path), data).unwrap()
+    }
+
+    #[test]
+    fn version_is_added_and_comments_are_kept() {
+        let migrated = migrate(
+            ProjectFormat::Ron,
+            "// my project\nProject(\n    name: \"test\", // the name\n)\n",
+        );
+        assert_eq!(migrated.from, 1);
+        assert_eq!(migrated.to, CURRENT_VERSION);
+        assert!(migrated.comments_kept);
+        assert_eq!(
+            migrated.data,
+            "// my project\nProject(\n    version: 2,\n    name: \"test\", // the name\n)\n"
+        );
+
+        let migrated = migrate(ProjectFormat::Toml, "# comment\nname = \"test\"\n");
+        assert_eq!(migrated.data, "# comment\nname = \"test\"\nversion = 2\n");
+
+        let migrated = migrate(ProjectFormat::Yaml, "name: test # comment\nversion: 1\n");
+        assert_eq!(migrated.data, "name: test # comment\nversion: 2\n");
+
+        let migrated = migrate(ProjectFormat::Json, "{\"name\": \"test\"}");
+        assert_eq!(migrated.data, "{\"version\": 2, \"name\": \"test\"}");
+    }
+
+    #[test]
+    fn capture_all_is_replaced_with_argv() {
+        let migrated = migrate(
+            ProjectFormat::Ron,
+            r#"Project(
+                name: "test",
+                commands: [
+                    Command(name: "run", capture_all: true, args: {"a": "b"}, shell: [Process(command: "cargo", args: ["run"])]),
+                ],
+            )"#,
+        );
+        assert!(!migrated.comments_kept);
+        assert_eq!(migrated.changes.len(), 2, "{:?}", migrated.changes);
+
+        let project =
+            parse_project_as(ProjectFormat::Ron, Path::new("hisho.ron"), &migrated.data).unwrap();
+        assert_eq!(project.version, CURRENT_VERSION);
+        assert_eq!(project.commands[0].shell[0].args, vec!["run", "[[argv]]"]);
+        #[allow(deprecated)]
+        let args = &project.commands[0].args;
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let result = migrate_source(
+            ProjectFormat::Ron,
+            Path::new("hisho.ron"),
+            "Project(name: \"test\", version: 99)",
+        );
+        assert!(matches!(
+            result,
+            Err(ProjectError::UnsupportedVersion { version: 99, .. })
+        ));
+    }
+}
@@ -0,0 +1,401 @@
+// This file 'overrides.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use crate::config_models::{
+    BuildStep, Command, Container, Environment, EnvironmentExport, EnvironmentRule,
+    EnvironmentSource, Isolation, Process, Project, SecretSource, Service, ServiceProtocol,
+    VariableSchema,
+};
+use crate::files;
+use crate::project::{ProjectError, ProjectFormat};
+
+/// File name, without extension, of the personal override file next to the project file
+pub const LOCAL_OVERRIDE_FILE_STEM: &str = "hisho.local";
+/// Directory of the user-level configuration, relative to the home directory
+pub const USER_CONFIG_DIR: &str = ".config/hisho";
+/// File name, without extension, of the user-level configuration
+pub const USER_CONFIG_FILE_STEM: &str = "config";
+
+/// The files each named entity of a project was defined in, keyed by kind and name
+///
+/// The first file is where the entity was defined, all following files have overridden it.
+pub type EntityOrigins = BTreeMap<(&'static str, String), Vec<PathBuf>>;
+
+/// A partial `Project`, every entity is merged into the project by its name, `environment_rules` replace the rules of the project.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename = "Project")]
+pub struct ProjectOverride {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub environments: Vec<EnvironmentOverride>,
+    #[serde(default)]
+    pub environment_rules: Option<Vec<EnvironmentRule>>,
+    #[serde(default)]
+    pub containers: Vec<ContainerOverride>,
+    #[serde(default)]
+    pub build: Vec<BuildStepOverride>,
+    #[serde(default)]
+    pub services: Vec<ServiceOverride>,
+    #[serde(default)]
+    pub commands: Vec<CommandOverride>,
+}
+
+/// A partial `Environment`, `values`, `prepend`, `append` and `schema` are merged by key, all other fields replace the project values.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename = "Environment")]
+pub struct EnvironmentOverride {
+    pub name: String,
+    #[serde(default)]
+    pub system: Option<Vec<String>>,
+    #[serde(default)]
+    pub inherits: Option<Vec<String>>,
+    #[serde(default)]
+    pub values: HashMap<String, String>,
+    #[serde(default)]
+    pub sources: Option<Vec<EnvironmentSource>>,
+    #[serde(default)]
+    pub secret_sources: Option<Vec<SecretSource>>,
+    #[serde(default)]
+    pub unset: Option<Vec<String>>,
+    #[serde(default)]
+    pub prepend: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub append: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub secret: Option<Vec<String>>,
+    #[serde(default)]
+    pub isolation: Option<Isolation>,
+    #[serde(default)]
+    pub schema: HashMap<String, VariableSchema>,
+}
+
+/// A `Container`, optionally replacing a container of the project with another name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename = "Container")]
+pub struct ContainerOverride {
+    pub name: String,
+    #[serde(default)]
+    pub replaces: Option<String>,
+}
+
+/// A partial `BuildStep`, all given fields replace the project values.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename = "BuildStep")]
+pub struct BuildStepOverride {
+    pub name: String,
+    #[serde(default)]
+    pub shell: Option<Vec<Process>>,
+    #[serde(default)]
+    pub depends_on: Option<Vec<String>>,
+    #[serde(default)]
+    pub input_files: Option<Vec<String>>,
+    #[serde(default)]
+    pub exports: Option<Vec<EnvironmentExport>>,
+}
+
+/// A partial `Service`, all given fields replace the project values.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename = "Service")]
+pub struct ServiceOverride {
+    pub name: String,
+    #[serde(default)]
+    pub protocol: Option<ServiceProtocol>,
+    #[serde(default)]
+    pub uri: Option<String>,
+}
+
+/// A partial `Command`, all given fields replace the project values.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename = "Command")]
+pub struct CommandOverride {
+    pub name: String,
+    #[serde(default)]
+    pub environment: Option<String>,
+    #[serde(default)]
+    pub shell: Option<Vec<Process>>,
+    #[serde(default)]
+    pub depends_on_build: Option<Vec<String>>,
+}
+
+/// Find the personal override file next to the given project file.
+///
+/// Any of the supported formats is accepted, like `hisho.local.ron` or `hisho.local.yaml`.
+pub fn find_local_override(project_file: &Path) -> Option<PathBuf> {
+    let dir = project_file.parent()?;
+    find_with_stem(dir, LOCAL_OVERRIDE_FILE_STEM)
+}
+
+/// Find the user-level configuration, like `~/.config/hisho/config.ron`.
+pub fn find_user_config() -> Option<PathBuf> {
+    let home_dir = files::get_home_dir()?;
+    find_with_stem(
+        files::string_to_path(&home_dir)
+            .join(USER_CONFIG_DIR)
+            .as_path(),
+        USER_CONFIG_FILE_STEM,
+    )
+}
+
+fn record_origin(origins: &mut EntityOrigins, kind: &'static str, name: &str, path: &Path) {
+    origins
+        .entry((kind, name.to_string()))
+        .or_default()
+        .push(path.to_path_buf());
+}
+
+fn find_with_stem(dir: &Path, stem: &str) -> Option<PathBuf> {
+    ProjectFormat::ALL
+        .iter()
+        .map(|format| dir.join(format!("{}.{}", stem, format.extension())))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Merge an override into the project.
+///
+/// Entities are matched by kind and name, entities that do not exist in the project are added
+/// when all required fields are given. The `origins` are updated with the path of the override.
+pub fn apply_override(
+    project: &mut Project,
+    origins: &mut EntityOrigins,
+    overrides: ProjectOverride,
+    path: &Path,
+) -> Result<(), ProjectError> {
+    let missing = |kind: &str, name: &str, field: &str| ProjectError::Override {
+        path: path.to_path_buf(),
+        message: format!(
+            "The {} '{}' does not exist in the project and is missing '{}'",
+            kind, name, field
+        ),
+    };
+
+    if let Some(name) = overrides.name {
+        project.name = name;
+    }
+    if let Some(environment_rules) = overrides.environment_rules {
+        project.environment_rules = environment_rules;
+    }
+
+    for o in overrides.environments {
+        record_origin(origins, "environment", &o.name, path);
+        match project.environments.iter_mut().find(|e| e.name == o.name) {
+            Some(env) => {
+                if let Some(system) = o.system {
+                    env.system = system;
+                }
+                if let Some(inherits) = o.inherits {
+                    env.inherits = inherits;
+                }
+                if let Some(sources) = o.sources {
+                    env.sources = sources;
+                }
+                if let Some(secret_sources) = o.secret_sources {
+                    env.secret_sources = secret_sources;
+                }
+                if let Some(unset) = o.unset {
+                    env.unset = unset;
+                }
+                if let Some(secret) = o.secret {
+                    env.secret = secret;
+                }
+                if o.isolation.is_some() {
+                    env.isolation = o.isolation;
+                }
+                env.values.extend(o.values);
+                env.prepend.extend(o.prepend);
+                env.append.extend(o.append);
+                env.schema.extend(o.schema);
+            }
+            None => {
+                let mut env = Environment::new(&o.name, o.inherits.unwrap_or_default(), o.values);
+                env.system = o.system.unwrap_or_default();
+                env.sources = o.sources.unwrap_or_default();
+                env.secret_sources = o.secret_sources.unwrap_or_default();
+                env.unset = o.unset.unwrap_or_default();
+                env.prepend = o.prepend;
+                env.append = o.append;
+                env.secret = o.secret.unwrap_or_default();
+                env.isolation = o.isolation;
+                env.schema = o.schema;
+                project.environments.push(env);
+            }
+        }
+    }
+
+    for o in overrides.containers {
+        let target = o.replaces.as_deref().unwrap_or(o.name.as_str());
+        match project.containers.iter_mut().find(|c| c.name == target) {
+            Some(container) => {
+                // a renamed container keeps the history of the container it replaces
+                if let Some(history) = origins.remove(&("container", target.to_string())) {
+                    origins.insert(("container", o.name.clone()), history);
+                }
+                record_origin(origins, "container", &o.name, path);
+                container.name = o.name;
+            }
+            None => {
+                record_origin(origins, "container", &o.name, path);
+                project.containers.push(Container { name: o.name });
+            }
+        }
+    }
+
+    for o in overrides.build {
+        record_origin(origins, "build step", &o.name, path);
+        match project.build.iter_mut().find(|b| b.name == o.name) {
+            Some(step) => {
+                if let Some(shell) = o.shell {
+                    step.shell = shell;
+                }
+                if let Some(depends_on) = o.depends_on {
+                    step.depends_on = depends_on;
+                }
+                if let Some(input_files) = o.input_files {
+                    step.input_files = input_files;
+                }
+                if let Some(exports) = o.exports {
+                    step.exports = exports;
+                }
+            }
+            None => project.build.push(BuildStep {
+                shell: o.shell.unwrap_or_default(),
+                depends_on: o.depends_on.unwrap_or_default(),
+                input_files: o.input_files.unwrap_or_default(),
+                exports: o.exports.unwrap_or_default(),
+                name: o.name,
+            }),
+        }
+    }
+
+    for o in overrides.services {
+        record_origin(origins, "service", &o.name, path);
+        match project.services.iter_mut().find(|s| s.name == o.name) {
+            Some(service) => {
+                if let Some(protocol) = o.protocol {
+                    service.protocol = protocol;
+                }
+                if let Some(uri) = o.uri {
+                    service.uri = uri;
+                }
+            }
+            None => project.services.push(Service {
+                protocol: o
+                    .protocol
+                    .ok_or_else(|| missing("service", &o.name, "protocol"))?,
+                uri: o.uri.ok_or_else(|| missing("service", &o.name, "uri"))?,
+                name: o.name,
+            }),
+        }
+    }
+
+    for o in overrides.commands {
+        record_origin(origins, "command", &o.name, path);
+        match project.commands.iter_mut().find(|c| c.name == o.name) {
+            Some(cmd) => {
+                if let Some(environment) = o.environment {
+                    cmd.environment = environment;
+                }
+                if let Some(shell) = o.shell {
+                    cmd.shell = shell;
+                }
+                if let Some(depends_on_build) = o.depends_on_build {
+                    cmd.depends_on_build = depends_on_build;
+                }
+            }
+            None => {
+                #[allow(deprecated)]
+                project.commands.push(Command {
+                    name: o.name,
+                    environment: o.environment.unwrap_or_default(),
+                    shell: o.shell.unwrap_or_default(),
+                    args: HashMap::new(),
+                    depends_on_build: o.depends_on_build.unwrap_or_default(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::{parse_project, parse_project_override};
+
+    #[test]
+    fn override_merges_by_name() {
+        let mut project = parse_project(
+            Path::new("hisho.ron"),
+            r#"Project(
+                name: "test",
+                environments: [Environment(name: "dev", values: {"A": "1", "B": "2"})],
+                containers: [Container(name: "postgres")],
+                services: [Service(name: "api", protocol: HTTP, uri: "http://localhost")],
+            )"#,
+        )
+        .unwrap();
+        let mut origins = EntityOrigins::new();
+        origins.insert(
+            ("environment", "dev".to_string()),
+            vec![PathBuf::from("hisho.ron")],
+        );
+
+        let overrides = parse_project_override(
+            Path::new("hisho.local.ron"),
+            r#"Project(
+                environments: [Environment(name: "dev", values: {"B": "local"})],
+                containers: [Container(name: "my-postgres", replaces: "postgres")],
+                services: [Service(name: "api", uri: "http://127.0.0.1:8080")],
+            )"#,
+        )
+        .unwrap();
+        apply_override(
+            &mut project,
+            &mut origins,
+            overrides,
+            Path::new("hisho.local.ron"),
+        )
+        .unwrap();
+
+        let env = &project.environments[0];
+        assert_eq!(env.values.get("A"), Some(&"1".to_string()));
+        assert_eq!(env.values.get("B"), Some(&"local".to_string()));
+        assert_eq!(project.containers[0].name, "my-postgres");
+        assert_eq!(project.services[0].uri, "http://127.0.0.1:8080");
+        assert_eq!(
+            origins.get(&("environment", "dev".to_string())),
+            Some(&vec![
+                PathBuf::from("hisho.ron"),
+                PathBuf::from("hisho.local.ron")
+            ])
+        );
+    }
+
+    #[test]
+    fn new_entity_requires_all_fields() {
+        let mut project = parse_project(Path::new("hisho.ron"), "Project(name: \"test\")").unwrap();
+        let overrides = parse_project_override(
+            Path::new("hisho.local.ron"),
+            "Project(services: [Service(name: \"api\", uri: \"http://localhost\")])",
+        )
+        .unwrap();
+        let err = apply_override(
+            &mut project,
+            &mut EntityOrigins::new(),
+            overrides,
+            Path::new("hisho.local.ron"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("is missing 'protocol'"));
+    }
+}
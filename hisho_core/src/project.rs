@@ -0,0 +1,934 @@
+// This file 'project.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+
+use crate::config_models::Project;
+use crate::migrate::CURRENT_VERSION;
+use crate::overrides::{EntityOrigins, ProjectOverride};
+use crate::{files, git, overrides};
+
+/// The file names that are searched for when discovering a project, in order of preference
+pub const PROJECT_FILE_NAMES: [&str; 5] = [
+    "hisho.ron",
+    "hisho.toml",
+    "hisho.yaml",
+    "hisho.yml",
+    "hisho.json",
+];
+/// Environment variable that overrides project discovery with the path to a project file
+pub const PROJECT_FILE_ENV: &str = "HISHO_FILE";
+
+/// Errors that can occur while loading a project file.
+#[derive(Debug)]
+pub enum ProjectError {
+    /// The project file does not exist or the path could not be resolved
+    NotFound { path: String, source: io::Error },
+    /// No project file was found in the start directory or any of its parents
+    NotDiscovered { start: PathBuf },
+    /// The parent directory of the project file could not be determined
+    NoWorkdir { path: PathBuf },
+    /// The project file exists but could not be read
+    Read { path: PathBuf, source: io::Error },
+    /// The format of the project file could not be determined from its extension
+    UnknownFormat { path: PathBuf },
+    /// The project file could not be parsed
+    Parse(Diagnostic),
+    /// The project could not be serialized into the given format
+    Serialize {
+        format: ProjectFormat,
+        message: String,
+    },
+    /// The project file could not be written
+    Write { path: PathBuf, source: io::Error },
+    /// A project file includes itself, directly or through other includes
+    IncludeCycle { chain: Vec<PathBuf> },
+    /// A workspace is a member of itself, directly or through the members of its members
+    MemberCycle { chain: Vec<PathBuf> },
+    /// An override file could not be merged into the project
+    Override { path: PathBuf, message: String },
+    /// Two included project files define an entity with the same name
+    DuplicateName {
+        kind: &'static str,
+        name: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    /// The project file has a newer version than this version of Hisho understands
+    UnsupportedVersion { path: PathBuf, version: u32 },
+}
+
+impl fmt::Display for ProjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectError::NotFound { path, source } => {
+                write!(f, "Could not find project file '{}': {}", path, source)
+            }
+            ProjectError::NotDiscovered { start } => write!(
+                f,
+                "Could not find any of '{}' in '{}' or any parent directory",
+                PROJECT_FILE_NAMES.join("', '"),
+                start.display()
+            ),
+            ProjectError::NoWorkdir { path } => write!(
+                f,
+                "Could not resolve parent directory of project file '{}'",
+                path.display()
+            ),
+            ProjectError::Read { path, source } => write!(
+                f,
+                "Could not read project file '{}': {}",
+                path.display(),
+                source
+            ),
+            ProjectError::UnknownFormat { path } => write!(
+                f,
+                "Unknown format of project file '{}', expected one of the extensions: {}",
+                path.display(),
+                ProjectFormat::ALL
+                    .iter()
+                    .map(|format| format.extension())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ),
+            ProjectError::Parse(diagnostic) => write!(
+                f,
+                "Could not parse project file '{}'\n{}",
+                diagnostic.path.display(),
+                diagnostic
+            ),
+            ProjectError::Serialize { format, message } => {
+                write!(f, "Could not serialize project as {}: {}", format, message)
+            }
+            ProjectError::Write { path, source } => write!(
+                f,
+                "Could not write project file '{}': {}",
+                path.display(),
+                source
+            ),
+            ProjectError::IncludeCycle { chain } => write!(
+                f,
+                "Project file includes itself: {}",
+                chain
+                    .iter()
+                    .map(|path| format!("'{}'", path.display()))
+                    .collect::<Vec<String>>()
+                    .join(" -> ")
+            ),
+            ProjectError::MemberCycle { chain } => write!(
+                f,
+                "Workspace is a member of itself: {}",
+                chain
+                    .iter()
+                    .map(|path| format!("'{}'", path.display()))
+                    .collect::<Vec<String>>()
+                    .join(" -> ")
+            ),
+            ProjectError::Override { path, message } => write!(
+                f,
+                "Could not apply override file '{}': {}",
+                path.display(),
+                message
+            ),
+            ProjectError::DuplicateName {
+                kind,
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "The {} '{}' is defined in '{}' and again in '{}'",
+                kind,
+                name,
+                first.display(),
+                second.display()
+            ),
+            ProjectError::UnsupportedVersion { path, version } => write!(
+                f,
+                "Project file '{}' has version {}, but this version of Hisho only supports up to version {}",
+                path.display(),
+                version,
+                CURRENT_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProjectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProjectError::NotFound { source, .. }
+            | ProjectError::Read { source, .. }
+            | ProjectError::Write { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// A message that points to a location inside a configuration file.
+///
+/// The `line` and `column` are 1-based, a value of `0` means that the location is unknown.
+/// When displayed, the offending line is printed with a caret below the column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    pub source_line: Option<String>,
+}
+
+impl Diagnostic {
+    /// Create a diagnostic for the given location and take the offending line from the source text.
+    pub fn new(path: &Path, source: &str, line: usize, column: usize, message: String) -> Self {
+        let source_line = if line > 0 {
+            source.lines().nth(line - 1).map(|l| l.to_string())
+        } else {
+            None
+        };
+        Diagnostic {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message,
+            source_line,
+        }
+    }
+
+    /// Create a diagnostic from a RON parser error.
+    pub fn from_ron(path: &Path, source: &str, error: &ron::error::SpannedError) -> Self {
+        Diagnostic::new(
+            path,
+            source,
+            error.position.line,
+            error.position.col,
+            error.code.to_string(),
+        )
+    }
+
+    /// Create a diagnostic from a byte offset into the source text.
+    pub fn from_offset(path: &Path, source: &str, offset: usize, message: String) -> Self {
+        let offset = offset.min(source.len());
+        let before = &source.as_bytes()[..offset];
+        let line = before.iter().filter(|b| **b == b'\n').count() + 1;
+        let line_start = before
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map(|pos| pos + 1)
+            .unwrap_or(0);
+        Diagnostic::new(path, source, line, offset - line_start + 1, message)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            return write!(f, "  --> {}\n  = {}", self.path.display(), self.message);
+        }
+        let line_no = self.line.to_string();
+        let gutter = " ".repeat(line_no.len());
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter,
+            self.path.display(),
+            self.line,
+            self.column
+        )?;
+        match &self.source_line {
+            Some(source_line) => {
+                // parsers report byte columns, convert them to a char offset for the caret
+                let byte_offset = self.column.saturating_sub(1).min(source_line.len());
+                let caret_offset = source_line
+                    .char_indices()
+                    .take_while(|(i, _)| *i < byte_offset)
+                    .count();
+                let indent = source_line
+                    .chars()
+                    .take(caret_offset)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect::<String>();
+                writeln!(f, "{} |", gutter)?;
+                writeln!(f, "{} | {}", line_no, source_line)?;
+                write!(f, "{} | {}^ {}", gutter, indent, self.message)
+            }
+            None => write!(f, "{} = {}", gutter, self.message),
+        }
+    }
+}
+
+/// The file formats a project file can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProjectFormat {
+    Ron,
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ProjectFormat {
+    pub const ALL: [ProjectFormat; 4] = [
+        ProjectFormat::Ron,
+        ProjectFormat::Toml,
+        ProjectFormat::Yaml,
+        ProjectFormat::Json,
+    ];
+
+    /// Determine the format from the extension of the given path.
+    pub fn from_path(path: &Path) -> Option<ProjectFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        ProjectFormat::from_name(extension.as_str())
+    }
+
+    /// Determine the format of a project file from its extension.
+    ///
+    /// Files without a known extension, like `-f hisho` or `HISHO_FILE=.hisho`, are RON.
+    pub fn for_project_file(path: &Path) -> ProjectFormat {
+        ProjectFormat::from_path(path).unwrap_or(ProjectFormat::Ron)
+    }
+
+    /// Determine the format from its name or file extension.
+    pub fn from_name(name: &str) -> Option<ProjectFormat> {
+        match name {
+            "ron" => Some(ProjectFormat::Ron),
+            "toml" => Some(ProjectFormat::Toml),
+            "yaml" | "yml" => Some(ProjectFormat::Yaml),
+            "json" => Some(ProjectFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ProjectFormat::Ron => "ron",
+            ProjectFormat::Toml => "toml",
+            ProjectFormat::Yaml => "yaml",
+            ProjectFormat::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for ProjectFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension().to_uppercase())
+    }
+}
+
+/// Loads a `Project` from a project file.
+///
+/// The loader resolves the given path, parses the file, merges all `includes` into the project
+/// and sets the `workdir` of the project to the directory that contains the project file.
+///
+/// Afterwards the user-level configuration and the personal `hisho.local.ron` next to the
+/// project file are merged over the project, see the `overrides` module.
+#[derive(Debug, Clone)]
+pub struct ProjectLoader {
+    path: String,
+    overrides: bool,
+    user_config: bool,
+    // the files of the workspaces this project is loaded as a member of, to detect cycles
+    workspaces: Vec<PathBuf>,
+}
+
+impl ProjectLoader {
+    pub fn new(path: &str) -> Self {
+        ProjectLoader {
+            path: path.to_string(),
+            overrides: true,
+            user_config: true,
+            workspaces: Vec::new(),
+        }
+    }
+
+    /// Enable or disable merging of the override files, enabled by default.
+    pub fn with_overrides(mut self, enabled: bool) -> Self {
+        self.overrides = enabled;
+        self
+    }
+
+    /// The path of the project file, as given to the loader.
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    /// Create a loader for the project that applies to the given directory.
+    ///
+    /// If the `HISHO_FILE` environment variable is set, its value is used as the project file.
+    /// Otherwise the project file is searched with `discover_project_file`.
+    pub fn discover(start: &Path) -> Result<Self, ProjectError> {
+        if let Ok(path) = std::env::var(PROJECT_FILE_ENV) {
+            if !path.is_empty() {
+                return Ok(ProjectLoader::new(path.as_str()));
+            }
+        }
+        discover_project_file(start)
+            .map(|path| ProjectLoader::new(path.to_string_lossy().as_ref()))
+            .ok_or_else(|| ProjectError::NotDiscovered {
+                start: start.to_path_buf(),
+            })
+    }
+
+    /// Load and parse the project file.
+    pub fn load(&self) -> Result<Project, ProjectError> {
+        self.load_with_origins().map(|(project, _)| project)
+    }
+
+    /// Load and parse the project file, and return the files each entity was defined in.
+    pub fn load_with_origins(&self) -> Result<(Project, EntityOrigins), ProjectError> {
+        let project_file =
+            files::resolve_path(self.path.clone()).map_err(|e| ProjectError::NotFound {
+                path: self.path.clone(),
+                source: e,
+            })?;
+        let workdir = project_file
+            .parent()
+            .ok_or_else(|| ProjectError::NoWorkdir {
+                path: project_file.clone(),
+            })?
+            .to_path_buf();
+
+        let mut stack: Vec<PathBuf> = Vec::new();
+        let (mut project, include_origins) = load_with_includes(&project_file, &mut stack)?;
+        let mut origins: EntityOrigins = include_origins
+            .into_iter()
+            .map(|(key, path)| (key, vec![path]))
+            .collect();
+
+        if self.overrides {
+            let mut override_files: Vec<PathBuf> = Vec::new();
+            if self.user_config {
+                override_files.extend(overrides::find_user_config());
+            }
+            override_files.extend(overrides::find_local_override(&project_file));
+            for override_file in override_files {
+                let project_override = read_project_override_file(&override_file)?;
+                overrides::apply_override(
+                    &mut project,
+                    &mut origins,
+                    project_override,
+                    &override_file,
+                )?;
+            }
+        }
+
+        project.workdir = workdir.to_string_lossy().to_string();
+        project.file = project_file.to_string_lossy().to_string();
+        let mut workspaces = self.workspaces.clone();
+        workspaces.push(project_file.clone());
+        project.member_projects = load_members(&project, &workdir, &workspaces, self.overrides)?;
+        Ok((project, origins))
+    }
+}
+
+/// Load all member projects of a workspace.
+///
+/// Members are paths relative to the workspace directory, pointing to a project file
+/// or to a directory that contains one of the `PROJECT_FILE_NAMES`.
+/// The `workspaces` are the files of the workspace and the workspaces it is a member of,
+/// a member that is one of them is a `ProjectError::MemberCycle`.
+fn load_members(
+    workspace: &Project,
+    workdir: &Path,
+    workspaces: &[PathBuf],
+    with_overrides: bool,
+) -> Result<Vec<Project>, ProjectError> {
+    let mut members: Vec<Project> = Vec::new();
+    let mut member_files: HashMap<String, PathBuf> = HashMap::new();
+    if let Some(workspace_file) = workspaces.last() {
+        member_files.insert(workspace.name.clone(), workspace_file.clone());
+    }
+
+    for member in &workspace.members {
+        let member_path = files::resolve_path(workdir.join(member).to_string_lossy().to_string())
+            .map_err(|e| ProjectError::NotFound {
+            path: member.clone(),
+            source: e,
+        })?;
+        let member_file = if member_path.is_dir() {
+            PROJECT_FILE_NAMES
+                .iter()
+                .map(|file_name| member_path.join(file_name))
+                .find(|candidate| candidate.is_file())
+                .ok_or_else(|| ProjectError::NotFound {
+                    path: member.clone(),
+                    source: io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "Directory does not contain a project file",
+                    ),
+                })?
+        } else {
+            member_path
+        };
+        if workspaces.contains(&member_file) {
+            let mut chain = workspaces.to_vec();
+            chain.push(member_file);
+            return Err(ProjectError::MemberCycle { chain });
+        }
+
+        // the user-level configuration only applies to the workspace itself
+        let loader = ProjectLoader {
+            path: member_file.to_string_lossy().to_string(),
+            overrides: with_overrides,
+            user_config: false,
+            workspaces: workspaces.to_vec(),
+        };
+        let project = loader.load()?;
+        if let Some(first) = member_files.get(&project.name) {
+            return Err(ProjectError::DuplicateName {
+                kind: "project",
+                name: project.name,
+                first: first.clone(),
+                second: member_file,
+            });
+        }
+        member_files.insert(project.name.clone(), member_file);
+        members.push(project);
+    }
+    Ok(members)
+}
+
+/// The file each named entity of a project was defined in, keyed by kind and name
+type Origins = HashMap<(&'static str, String), PathBuf>;
+
+/// Read and parse a single project file, without resolving its includes.
+pub fn read_project_file(path: &Path) -> Result<Project, ProjectError> {
+    let data = fs::read_to_string(path).map_err(|e| ProjectError::Read {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    parse_project(path, data.as_str())
+}
+
+/// Read and parse an override file like `hisho.local.ron`.
+pub fn read_project_override_file(path: &Path) -> Result<ProjectOverride, ProjectError> {
+    let data = fs::read_to_string(path).map_err(|e| ProjectError::Read {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    parse_project_override(path, data.as_str())
+}
+
+fn load_with_includes(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<(Project, Origins), ProjectError> {
+    let mut project = read_project_file(path)?;
+    let mut origins = Origins::new();
+    for (kind, name) in entity_names(&project) {
+        origins.entry((kind, name)).or_insert(path.to_path_buf());
+    }
+
+    stack.push(path.to_path_buf());
+    let base_dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+    for include in project.includes.clone() {
+        let include_path = files::resolve_path(
+            base_dir.join(&include).to_string_lossy().to_string(),
+        )
+        .map_err(|e| ProjectError::NotFound {
+            path: include.clone(),
+            source: e,
+        })?;
+        if stack.contains(&include_path) {
+            let mut chain = stack.clone();
+            chain.push(include_path);
+            return Err(ProjectError::IncludeCycle { chain });
+        }
+        let (included, included_origins) = load_with_includes(&include_path, stack)?;
+        merge_project(&mut project, &mut origins, included, &included_origins)?;
+    }
+    stack.pop();
+
+    Ok((project, origins))
+}
+
+/// Merge the entities and environment rules of an included project into the project.
+///
+/// Entities that come from the same file as an already merged entity are skipped,
+/// so a file that is included twice is only merged once, the same goes for equal rules.
+fn merge_project(
+    project: &mut Project,
+    origins: &mut Origins,
+    included: Project,
+    included_origins: &Origins,
+) -> Result<(), ProjectError> {
+    // check all names first, so the project is not modified when the merge fails
+    let mut merged: Vec<(&'static str, String)> = Vec::new();
+    for (kind, name) in entity_names(&included) {
+        let key = (kind, name);
+        let second = included_origins.get(&key).cloned().unwrap_or_default();
+        match origins.get(&key) {
+            Some(first) if *first == second => {}
+            Some(first) => {
+                return Err(ProjectError::DuplicateName {
+                    kind,
+                    name: key.1,
+                    first: first.clone(),
+                    second,
+                });
+            }
+            None => merged.push(key),
+        }
+    }
+    let is_new = |kind: &'static str, name: &String| merged.contains(&(kind, name.clone()));
+
+    project.environments.extend(
+        included
+            .environments
+            .into_iter()
+            .filter(|e| is_new("environment", &e.name)),
+    );
+    project.containers.extend(
+        included
+            .containers
+            .into_iter()
+            .filter(|c| is_new("container", &c.name)),
+    );
+    project.build.extend(
+        included
+            .build
+            .into_iter()
+            .filter(|b| is_new("build step", &b.name)),
+    );
+    project.services.extend(
+        included
+            .services
+            .into_iter()
+            .filter(|s| is_new("service", &s.name)),
+    );
+    project.commands.extend(
+        included
+            .commands
+            .into_iter()
+            .filter(|c| is_new("command", &c.name)),
+    );
+    // rules have no name, they follow the rules of the including file in the order of the includes
+    for rule in included.environment_rules {
+        if !project.environment_rules.contains(&rule) {
+            project.environment_rules.push(rule);
+        }
+    }
+
+    for key in merged {
+        let origin = included_origins.get(&key).cloned().unwrap_or_default();
+        origins.insert(key, origin);
+    }
+    Ok(())
+}
+
+/// List the kind and name of all named entities of a project.
+fn entity_names(project: &Project) -> Vec<(&'static str, String)> {
+    let mut names: Vec<(&'static str, String)> = Vec::new();
+    names.extend(
+        project
+            .environments
+            .iter()
+            .map(|e| ("environment", e.name.clone())),
+    );
+    names.extend(
+        project
+            .containers
+            .iter()
+            .map(|c| ("container", c.name.clone())),
+    );
+    names.extend(project.build.iter().map(|b| ("build step", b.name.clone())));
+    names.extend(project.services.iter().map(|s| ("service", s.name.clone())));
+    names.extend(project.commands.iter().map(|c| ("command", c.name.clone())));
+    names
+}
+
+/// Search the given directory and its parents for a project file.
+///
+/// The search stops at the root of the git repository that contains `start`,
+/// or at the root of the filesystem when `start` is not inside a git repository.
+pub fn discover_project_file(start: &Path) -> Option<PathBuf> {
+    let start = fs::canonicalize(start).ok()?;
+    let repo_root = git::find_repo_root(&start);
+    for dir in start.ancestors() {
+        for file_name in PROJECT_FILE_NAMES {
+            let candidate = dir.join(file_name);
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+        }
+        if repo_root.as_deref() == Some(dir) {
+            break;
+        }
+    }
+    None
+}
+
+/// Parse the text of a project file.
+///
+/// The format is chosen by the extension of the given path, see `ProjectFormat::for_project_file`,
+/// apart from that the path is only used for diagnostics.
+pub fn parse_project(path: &Path, data: &str) -> Result<Project, ProjectError> {
+    let format = ProjectFormat::for_project_file(path);
+    parse_project_as(format, path, data)
+}
+
+/// Parse the text of a project file in the given format.
+pub fn parse_project_as(
+    format: ProjectFormat,
+    path: &Path,
+    data: &str,
+) -> Result<Project, ProjectError> {
+    parse_as::<Project>(format, path, data)
+}
+
+/// Parse the text of an override file, the format is chosen like for `parse_project`.
+pub fn parse_project_override(path: &Path, data: &str) -> Result<ProjectOverride, ProjectError> {
+    let format = ProjectFormat::for_project_file(path);
+    parse_as::<ProjectOverride>(format, path, data)
+}
+
+pub(crate) fn parse_as<T: DeserializeOwned>(
+    format: ProjectFormat,
+    path: &Path,
+    data: &str,
+) -> Result<T, ProjectError> {
+    let result = match format {
+        ProjectFormat::Ron => ron::Options::default()
+            .with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+            .from_str::<T>(data)
+            .map_err(|e| Diagnostic::from_ron(path, data, &e)),
+        ProjectFormat::Toml => toml::from_str::<T>(data).map_err(|e| {
+            let offset = e.span().map(|span| span.start).unwrap_or(0);
+            Diagnostic::from_offset(path, data, offset, e.message().to_string())
+        }),
+        ProjectFormat::Yaml => serde_yaml::from_str::<T>(data).map_err(|e| {
+            let (line, column) = e
+                .location()
+                .map(|location| (location.line(), location.column()))
+                .unwrap_or((0, 0));
+            Diagnostic::new(path, data, line, column, e.to_string())
+        }),
+        ProjectFormat::Json => serde_json::from_str::<T>(data)
+            .map_err(|e| Diagnostic::new(path, data, e.line(), e.column(), e.to_string())),
+    };
+    result.map_err(ProjectError::Parse)
+}
+
+/// Serialize a project into the text of a project file in the given format.
+pub fn serialize_project(project: &Project, format: ProjectFormat) -> Result<String, ProjectError> {
+    let result = match format {
+        ProjectFormat::Ron => ron::ser::to_string_pretty(
+            project,
+            ron::ser::PrettyConfig::default().struct_names(true),
+        )
+        .map_err(|e| e.to_string()),
+        ProjectFormat::Toml => toml::to_string_pretty(project).map_err(|e| e.to_string()),
+        ProjectFormat::Yaml => serde_yaml::to_string(project).map_err(|e| e.to_string()),
+        ProjectFormat::Json => serde_json::to_string_pretty(project).map_err(|e| e.to_string()),
+    };
+    result.map_err(|message| ProjectError::Serialize { format, message })
+}
+
+/// Convert a project file into another format.
+///
+/// The formats of both files are chosen by their extensions.
+pub fn convert_project_file(input: &Path, output: &Path) -> Result<(), ProjectError> {
+    let output_format =
+        ProjectFormat::from_path(output).ok_or_else(|| ProjectError::UnknownFormat {
+            path: output.to_path_buf(),
+        })?;
+    let project = read_project_file(input)?;
+    let converted = serialize_project(&project, output_format)?;
+    fs::write(output, converted).map_err(|e| ProjectError::Write {
+        path: output.to_path_buf(),
+        source: e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_minimal_project() {
+        let project = parse_project(Path::new("hisho.ron"), "Project(name: \"test\")").unwrap();
+        assert_eq!(project.name, "test");
+        assert!(project.commands.is_empty());
+    }
+
+    #[test]
+    fn files_without_known_extension_are_ron() {
+        for path in ["hisho", ".hisho", "project.conf"] {
+            let project = parse_project(Path::new(path), "Project(name: \"test\")").unwrap();
+            assert_eq!(project.name, "test");
+        }
+    }
+
+    #[test]
+    fn discover_project_file_in_parent() {
+        let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let expected = fs::canonicalize(crate_dir.join("..").join("hisho.ron")).unwrap();
+        let found = discover_project_file(&crate_dir.join("src")).unwrap();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn round_trip_all_formats() {
+        let data =
+            fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("../hisho.ron")).unwrap();
+        let project = parse_project(Path::new("hisho.ron"), data.as_str()).unwrap();
+        for format in ProjectFormat::ALL {
+            let serialized = serialize_project(&project, format).unwrap();
+            let parsed = parse_project_as(format, Path::new("hisho"), serialized.as_str())
+                .unwrap_or_else(|e| panic!("{}: {}", format, e));
+            assert_eq!(parsed.name, project.name);
+            assert_eq!(parsed.build, project.build);
+            assert_eq!(parsed.commands.len(), project.commands.len());
+            assert_eq!(parsed.environments.len(), project.environments.len());
+        }
+    }
+
+    #[test]
+    fn toml_error_points_to_location() {
+        let data = "name = \"test\"\ncommands = 5\n";
+        let err = parse_project(Path::new("hisho.toml"), data).unwrap_err();
+        if let ProjectError::Parse(diagnostic) = err {
+            assert_eq!(diagnostic.line, 2);
+            assert_eq!(diagnostic.column, 12);
+        } else {
+            panic!("expected a parse error, got {:?}", err);
+        }
+    }
+
+    #[test]
+    fn merge_reports_duplicate_names() {
+        let mut project = parse_project(
+            Path::new("hisho.ron"),
+            "Project(name: \"main\", build: [BuildStep(name: \"debug\", shell: [])])",
+        )
+        .unwrap();
+        let mut origins = Origins::new();
+        for key in entity_names(&project) {
+            origins.insert(key, PathBuf::from("hisho.ron"));
+        }
+
+        let included = parse_project(
+            Path::new("common.ron"),
+            "Project(name: \"common\", build: [BuildStep(name: \"debug\", shell: [])])",
+        )
+        .unwrap();
+        let mut included_origins = Origins::new();
+        for key in entity_names(&included) {
+            included_origins.insert(key, PathBuf::from("common.ron"));
+        }
+
+        let err =
+            merge_project(&mut project, &mut origins, included, &included_origins).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The build step 'debug' is defined in 'hisho.ron' and again in 'common.ron'"
+        );
+        assert_eq!(project.build.len(), 1);
+    }
+
+    #[test]
+    fn included_environment_rules_are_appended() {
+        let workdir = std::env::temp_dir().join(format!("hisho-rules-{}", std::process::id()));
+        fs::create_dir_all(&workdir).unwrap();
+        let rules = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| {
+                    format!(
+                        "EnvironmentRule(environment: \"{}\", branch: \"{}\")",
+                        name, name
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        fs::write(
+            workdir.join("hisho.ron"),
+            format!(
+                "Project(name: \"main\", includes: [\"ci.ron\", \"release.ron\"], environment_rules: [{}])",
+                rules(&["dev"])
+            ),
+        )
+        .unwrap();
+        fs::write(
+            workdir.join("ci.ron"),
+            format!(
+                "Project(name: \"ci\", includes: [\"release.ron\"], environment_rules: [{}])",
+                rules(&["ci"])
+            ),
+        )
+        .unwrap();
+        fs::write(
+            workdir.join("release.ron"),
+            format!(
+                "Project(name: \"release\", environment_rules: [{}])",
+                rules(&["staging", "prod"])
+            ),
+        )
+        .unwrap();
+
+        let (project, _) = load_with_includes(&workdir.join("hisho.ron"), &mut Vec::new()).unwrap();
+        let environments: Vec<&str> = project
+            .environment_rules
+            .iter()
+            .map(|rule| rule.environment.as_str())
+            .collect();
+        assert_eq!(environments, vec!["dev", "ci", "staging", "prod"]);
+        let _ = fs::remove_dir_all(&workdir);
+    }
+
+    #[test]
+    fn member_cycles_are_reported() {
+        let workdir = std::env::temp_dir().join(format!("hisho-members-{}", std::process::id()));
+        fs::create_dir_all(workdir.join("api")).unwrap();
+        fs::write(
+            workdir.join("hisho.ron"),
+            "Project(name: \"workspace\", members: [\"api\"])",
+        )
+        .unwrap();
+        fs::write(
+            workdir.join("api").join("hisho.ron"),
+            "Project(name: \"api\", members: [\"..\"])",
+        )
+        .unwrap();
+
+        let err = ProjectLoader::new(&workdir.join("hisho.ron").to_string_lossy())
+            .with_overrides(false)
+            .load()
+            .unwrap_err();
+        let _ = fs::remove_dir_all(&workdir);
+        match err {
+            ProjectError::MemberCycle { chain } => {
+                assert_eq!(chain.len(), 3);
+                assert_eq!(chain.first(), chain.last());
+            }
+            _ => panic!("expected a member cycle, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn parse_error_points_to_location() {
+        let data = "Project(\n  name: \"test\",\n  commands: 5,\n)";
+        let err = parse_project(Path::new("hisho.ron"), data).unwrap_err();
+        if let ProjectError::Parse(diagnostic) = err {
+            assert_eq!(diagnostic.line, 3);
+            assert_eq!(diagnostic.source_line, Some("  commands: 5,".to_string()));
+            let rendered = diagnostic.to_string();
+            assert!(rendered.contains("--> hisho.ron:3:"));
+            assert!(rendered.contains("3 |   commands: 5,"));
+            assert!(rendered.contains("  | ") && rendered.contains('^'));
+        } else {
+            panic!("expected a parse error, got {:?}", err);
+        }
+    }
+}
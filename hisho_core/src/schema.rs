@@ -0,0 +1,59 @@
+// This file 'schema.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+
+use crate::config_models::Project;
+
+/// Generate the JSON Schema of a project file.
+///
+/// The schema is generated from the `config_models` types,
+/// including the defaults and the doc comments as descriptions.
+pub fn project_schema() -> RootSchema {
+    schema_for!(Project)
+}
+
+/// Generate the JSON Schema of a project file as pretty-printed JSON.
+pub fn project_schema_json() -> String {
+    serde_json::to_string_pretty(&project_schema()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_describes_project() {
+        let schema: serde_json::Value = serde_json::from_str(&project_schema_json()).unwrap();
+        assert_eq!(schema["title"], "Project");
+        assert_eq!(schema["required"], serde_json::json!(["name"]));
+        assert_eq!(
+            schema["properties"]["environments"]["default"],
+            serde_json::json!([])
+        );
+        for definition in [
+            "Environment",
+            "Command",
+            "Process",
+            "BuildStep",
+            "Service",
+            "Container",
+        ] {
+            assert!(
+                schema["definitions"][definition]["description"].is_string(),
+                "{} has no description",
+                definition
+            );
+        }
+        assert_eq!(
+            schema["definitions"]["Command"]["properties"]["args"]["deprecated"],
+            true
+        );
+    }
+}
@@ -0,0 +1,143 @@
+// This file 'secrets.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use crate::config_models::SecretSource;
+use crate::files;
+use crate::overrides::USER_CONFIG_DIR;
+
+/// File name of the default age identity, in the user-level configuration directory
+pub const AGE_IDENTITY_FILE: &str = "age.txt";
+/// Executable used to decrypt age files, `rage` is a drop-in replacement
+pub const AGE_COMMAND: &str = "age";
+
+/// Load the variables of a secret provider.
+///
+/// The loaded values are not marked as secret, see `log::mark_secret`.
+/// Paths are relative to `workdir`, a leading `~` is resolved to the home directory.
+/// Variables in decrypted .env files are returned as-is, without interpolation.
+pub fn load_secret(
+    source: &SecretSource,
+    workdir: &Path,
+) -> Result<HashMap<String, String>, String> {
+    match source {
+        SecretSource::AgeFile { path, identity } => {
+            let identity = match identity {
+                Some(identity) => resolve_path(identity, workdir),
+                None => default_age_identity()
+                    .ok_or_else(|| "Could not find the home directory".to_string())?,
+            };
+            let args = vec![
+                "--decrypt".to_string(),
+                "--identity".to_string(),
+                identity.to_string_lossy().to_string(),
+                resolve_path(path, workdir).to_string_lossy().to_string(),
+            ];
+            let data = run_command(AGE_COMMAND, &args, workdir)?;
+            let values = dotenv_parser::parse_dotenv(&data)
+                .map_err(|e| format!("Could not parse the decrypted file: {}", e))?;
+            Ok(values.into_iter().collect())
+        }
+        SecretSource::Command {
+            name,
+            command,
+            args,
+        } => {
+            let value = run_command(command, args, workdir)?;
+            Ok(HashMap::from([(name.clone(), without_newline(value))]))
+        }
+        SecretSource::File { name, path } => {
+            let value = fs::read_to_string(resolve_path(path, workdir))
+                .map_err(|e| format!("Could not read '{}': {}", path, e))?;
+            Ok(HashMap::from([(name.clone(), without_newline(value))]))
+        }
+    }
+}
+
+/// The default age identity, like `~/.config/hisho/age.txt`
+pub fn default_age_identity() -> Option<PathBuf> {
+    let home_dir = files::get_home_dir()?;
+    Some(
+        files::string_to_path(&home_dir)
+            .join(USER_CONFIG_DIR)
+            .join(AGE_IDENTITY_FILE),
+    )
+}
+
+/// Run a command and return its standard output.
+///
+/// Standard input is closed, so the command can not consume the input of hisho, like the messages of the language server.
+/// Standard error is inherited, commands like `age` ask for a passphrase on the terminal.
+pub(crate) fn run_command(
+    command: &str,
+    args: &[String],
+    workdir: &Path,
+) -> Result<String, String> {
+    let output = std::process::Command::new(command)
+        .args(args)
+        .current_dir(workdir)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Could not execute '{}': {}", command, e))?;
+    if !output.status.success() {
+        return Err(format!("'{}' failed ({})", command, output.status));
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|_| format!("The output of '{}' is not valid UTF-8", command))
+}
+
+fn resolve_path(path: &str, workdir: &Path) -> PathBuf {
+    match (path.strip_prefix('~'), files::get_home_dir()) {
+        (Some(rest), Some(home_dir)) => {
+            files::string_to_path(&home_dir).join(rest.trim_start_matches(['/', '\\']))
+        }
+        _ => workdir.join(path),
+    }
+}
+
+/// Remove a single trailing newline, like the one `pass` and `echo` print.
+fn without_newline(mut value: String) -> String {
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_and_commands_lose_one_newline() {
+        assert_eq!(without_newline("secret\n".to_string()), "secret");
+        assert_eq!(without_newline("secret\r\n".to_string()), "secret");
+        assert_eq!(without_newline("secret\n\n".to_string()), "secret\n");
+    }
+
+    #[test]
+    fn command_output_is_loaded() {
+        let values = load_secret(
+            &SecretSource::Command {
+                name: "TOKEN".to_string(),
+                command: "echo".to_string(),
+                args: vec!["abc".to_string()],
+            },
+            Path::new("."),
+        )
+        .unwrap();
+        assert_eq!(values.get("TOKEN"), Some(&"abc".to_string()));
+    }
+}
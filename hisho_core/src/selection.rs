@@ -0,0 +1,294 @@
+// This file 'selection.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Choose the environment for commands and programs.
+//!
+//! In order of precedence, the environment is
+//! 1. the one requested on the command line, like `hisho run -e staging test`
+//! 2. the one pinned by the `Command.environment`
+//! 3. the default environment of the project, see `state::default_environment`
+//! 4. the one of the first matching `Project.environment_rules`
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::config_models::{EnvironmentRule, Project};
+use crate::{files, git, state};
+
+/// What the environment rules are matched against.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Facts {
+    /// The current git branch, empty outside of a git repository
+    pub branch: String,
+    /// The name of the host, empty if it is unknown
+    pub hostname: String,
+    /// The operating system, like `linux`
+    pub os: String,
+    /// The system variables
+    pub variables: HashMap<String, String>,
+}
+
+impl Facts {
+    /// The facts of the current host and the git repository of `dir`.
+    pub fn current(dir: &Path) -> Facts {
+        Facts {
+            branch: git::fetch_repo_vars(dir)
+                .remove("branch")
+                .unwrap_or_default(),
+            hostname: hostname().unwrap_or_default(),
+            os: std::env::consts::OS.to_string(),
+            variables: std::env::vars().collect(),
+        }
+    }
+}
+
+/// Why an environment was chosen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Choice {
+    /// It was requested on the command line
+    Requested,
+    /// It is the environment of the command
+    Pinned,
+    /// It is the default environment of the project
+    Default,
+    /// The rule with the index in `environment_rules` matched
+    Rule(usize),
+    /// Nothing chose an environment
+    Nothing,
+}
+
+/// The chosen environment and why it was chosen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    /// Name of the environment, empty if nothing chose one
+    pub environment: String,
+    pub choice: Choice,
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.choice {
+            Choice::Requested => write!(
+                f,
+                "environment '{}', requested on the command line",
+                self.environment
+            ),
+            Choice::Pinned => write!(f, "environment '{}' of the command", self.environment),
+            Choice::Default => write!(
+                f,
+                "environment '{}', the default of the project set with 'hisho env use'",
+                self.environment
+            ),
+            Choice::Rule(index) => write!(
+                f,
+                "environment '{}' of environment rule {}",
+                self.environment,
+                index + 1
+            ),
+            Choice::Nothing => write!(f, "no environment"),
+        }
+    }
+}
+
+/// Choose the environment of a command or program in the project.
+///
+/// The `requested` environment is the one from the command line, the `pinned` one is the
+/// environment of the command, both are ignored if they are empty.
+/// The git branch and the other facts are only collected if the rules are needed.
+pub fn select_environment(project: &Project, requested: Option<&str>, pinned: &str) -> Selection {
+    let selection = |environment: &str, choice: Choice| Selection {
+        environment: environment.to_string(),
+        choice,
+    };
+    if let Some(requested) = requested.filter(|requested| !requested.is_empty()) {
+        return selection(requested, Choice::Requested);
+    }
+    if !pinned.is_empty() {
+        return selection(pinned, Choice::Pinned);
+    }
+    let workdir = files::string_to_path(&project.workdir);
+    if let Some(default) = state::default_environment(&workdir) {
+        return selection(&default, Choice::Default);
+    }
+    if project.environment_rules.is_empty() {
+        return selection("", Choice::Nothing);
+    }
+    match matching_rule(&project.environment_rules, &Facts::current(&workdir)) {
+        Some(index) => selection(
+            &project.environment_rules[index].environment,
+            Choice::Rule(index),
+        ),
+        None => selection("", Choice::Nothing),
+    }
+}
+
+/// The index of the first rule that matches the facts.
+pub fn matching_rule(rules: &[EnvironmentRule], facts: &Facts) -> Option<usize> {
+    rules
+        .iter()
+        .position(|rule| rule_mismatches(rule, facts).is_empty())
+}
+
+/// Describe why a rule does not match the facts, empty if it matches.
+pub fn rule_mismatches(rule: &EnvironmentRule, facts: &Facts) -> Vec<String> {
+    let mut mismatches: Vec<String> = Vec::new();
+    if let Some(pattern) = &rule.branch {
+        if !matches_pattern(pattern, &facts.branch) {
+            mismatches.push(format!(
+                "the branch '{}' does not match '{}'",
+                facts.branch, pattern
+            ));
+        }
+    }
+    if let Some(pattern) = &rule.hostname {
+        if !matches_pattern(pattern, &facts.hostname) {
+            mismatches.push(format!(
+                "the hostname '{}' does not match '{}'",
+                facts.hostname, pattern
+            ));
+        }
+    }
+    let mut variables: Vec<(&String, &String)> = rule.variables.iter().collect();
+    variables.sort();
+    for (name, pattern) in variables {
+        match facts.variables.get(name) {
+            Some(value) if matches_pattern(pattern, value) => {}
+            Some(value) => mismatches.push(format!(
+                "the variable '{}' is '{}', which does not match '{}'",
+                name, value, pattern
+            )),
+            None => mismatches.push(format!("the variable '{}' is not set", name)),
+        }
+    }
+    if let Some(os) = &rule.os {
+        if !os.eq_ignore_ascii_case(&facts.os) {
+            mismatches.push(format!(
+                "the operating system is '{}', not '{}'",
+                facts.os, os
+            ));
+        }
+    }
+    mismatches
+}
+
+/// Explain for each rule of the project if it matches, and which environment is chosen.
+pub fn explain_selection(project: &Project, selection: &Selection) -> Vec<String> {
+    let mut lines = vec![format!("Using {}", selection)];
+    if project.environment_rules.is_empty() {
+        return lines;
+    }
+    if !matches!(selection.choice, Choice::Rule(_) | Choice::Nothing) {
+        lines.push("The environment rules are not used:".to_string());
+    }
+    let facts = Facts::current(&files::string_to_path(&project.workdir));
+    for (index, rule) in project.environment_rules.iter().enumerate() {
+        let mismatches = rule_mismatches(rule, &facts);
+        let result = if mismatches.is_empty() {
+            "matches".to_string()
+        } else {
+            mismatches.join(", ")
+        };
+        lines.push(format!(
+            "\trule {} for '{}': {}",
+            index + 1,
+            rule.environment,
+            result
+        ));
+    }
+    lines
+}
+
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    pattern == value || glob::Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(value))
+}
+
+/// The name of the host, from `COMPUTERNAME` on Windows, the kernel on Linux or the `hostname` command.
+fn hostname() -> Option<String> {
+    if cfg!(windows) {
+        return std::env::var("COMPUTERNAME").ok();
+    }
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| {
+            std::process::Command::new("hostname")
+                .output()
+                .ok()
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+        })
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        environment: &str,
+        branch: Option<&str>,
+        variables: &[(&str, &str)],
+    ) -> EnvironmentRule {
+        EnvironmentRule {
+            environment: environment.to_string(),
+            branch: branch.map(str::to_string),
+            variables: variables
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..EnvironmentRule::default()
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = vec![
+            rule("ci", None, &[("CI", "true")]),
+            rule("dev", Some("develop"), &[]),
+            rule("staging", Some("release/*"), &[]),
+            rule("local", None, &[]),
+        ];
+        let facts = Facts {
+            branch: "release/1.2".to_string(),
+            os: "linux".to_string(),
+            ..Facts::default()
+        };
+        assert_eq!(matching_rule(&rules, &facts), Some(2));
+        assert_eq!(
+            rule_mismatches(&rules[0], &facts),
+            vec!["the variable 'CI' is not set".to_string()]
+        );
+        assert_eq!(
+            rule_mismatches(&rules[1], &facts),
+            vec!["the branch 'release/1.2' does not match 'develop'".to_string()]
+        );
+
+        let facts = Facts {
+            branch: "main".to_string(),
+            variables: HashMap::from([("CI".to_string(), "true".to_string())]),
+            ..Facts::default()
+        };
+        assert_eq!(matching_rule(&rules, &facts), Some(0));
+        assert_eq!(matching_rule(&rules[1..3], &facts), None);
+    }
+
+    #[test]
+    fn operating_system_ignores_case() {
+        let rule = EnvironmentRule {
+            environment: "win".to_string(),
+            os: Some("Windows".to_string()),
+            ..EnvironmentRule::default()
+        };
+        let facts = Facts {
+            os: "windows".to_string(),
+            ..Facts::default()
+        };
+        assert!(rule_mismatches(&rule, &facts).is_empty());
+    }
+}
@@ -28,7 +28,7 @@ pub async fn are_running(services: &Services) -> bool {
 }
 
 async fn is_running(service: &Service) -> bool {
-    return match service.protocol {
+    match service.protocol {
         ServiceProtocol::HTTP => match reqwest::get(service.uri.as_str()).await {
             Ok(response) => response.status().is_success(),
             Err(e) => {
@@ -52,7 +52,7 @@ async fn is_running(service: &Service) -> bool {
                 false
             }
         },
-    };
+    }
 }
 
 #[cfg(test)]
@@ -87,7 +87,7 @@ mod tests {
             protocol: ServiceProtocol::HTTP,
             uri: "http://172.32.137.254:31330/status".to_string(),
         };
-        assert_eq!(is_running(&test_service).await, false);
+        assert!(!is_running(&test_service).await);
     }
 
     #[tokio::test]
@@ -97,6 +97,6 @@ mod tests {
             protocol: ServiceProtocol::TCP,
             uri: "172.32.137.254:31330".to_string(),
         };
-        assert_eq!(is_running(&test_service).await, false);
+        assert!(!is_running(&test_service).await);
     }
 }
@@ -7,14 +7,25 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::collections::HashMap;
-use std::io;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
 
-use crate::config_models::Process;
-use crate::log;
+use tempfile::TempDir;
+
+use crate::config_models::{Isolation, Process};
+use crate::{files, log};
 
 /// Execute a process with the given environment and return the exit status
-pub fn exec(process: &Process, env: Option<&HashMap<String, String>>) -> io::Result<ExitStatus> {
+///
+/// The `isolation` decides which variables of the system the process inherits,
+/// the variables of the given environment are always passed.
+pub fn exec(
+    process: &Process,
+    env: Option<&HashMap<String, String>>,
+    isolation: Isolation,
+) -> io::Result<ExitStatus> {
     // execute the command in /bin/sh
     let mut proc_command = std::process::Command::new(process.command.clone());
     proc_command.args(process.args.clone());
@@ -23,6 +34,20 @@ pub fn exec(process: &Process, env: Option<&HashMap<String, String>>) -> io::Res
         proc_command.current_dir(process.cwd.clone());
     }
 
+    match isolation {
+        Isolation::Inherit => {}
+        Isolation::Clear => {
+            proc_command.env_clear();
+        }
+        Isolation::Minimal => {
+            proc_command.env_clear();
+            for name in Isolation::MINIMAL_BASELINE {
+                if let Some(value) = std::env::var_os(name) {
+                    proc_command.env(name, value);
+                }
+            }
+        }
+    }
     if let Some(env) = env {
         proc_command.envs(env.clone());
     }
@@ -56,3 +81,153 @@ pub fn exec(process: &Process, env: Option<&HashMap<String, String>>) -> io::Res
     }
     proc_result
 }
+
+/// Name of the variable with the prompt marker of an interactive shell, like `(hisho:api/dev)`
+pub const PROMPT_MARKER_VARIABLE: &str = "HISHO_PROMPT";
+
+/// An interactive shell, created by `interactive_shell`.
+///
+/// The startup files of the shell are removed when it is dropped, so it must be kept until the shell exited.
+#[derive(Debug)]
+pub struct InteractiveShell {
+    /// The process of the shell
+    pub process: Process,
+    /// The variables the process must be executed with
+    pub env: HashMap<String, String>,
+    // only kept to remove the startup files when the shell is dropped
+    _startup_dir: Option<TempDir>,
+}
+
+/// Create the process of an interactive shell whose prompt starts with the `marker`.
+///
+/// The shell is taken from `SHELL`, or `COMSPEC` on Windows.
+/// For bash and zsh a startup file is written into a new private temporary directory that loads
+/// the usual startup files of the user and then prefixes the prompt, fish gets an init command,
+/// other shells get `PS1` or `PROMPT`.
+pub fn interactive_shell(marker: &str) -> io::Result<InteractiveShell> {
+    let mut env: HashMap<String, String> = HashMap::new();
+    env.insert(PROMPT_MARKER_VARIABLE.to_string(), marker.to_string());
+
+    if cfg!(windows) {
+        let command = std::env::var("COMSPEC").unwrap_or_else(|_| "cmd.exe".to_string());
+        env.insert("PROMPT".to_string(), format!("{} $P$G", marker));
+        return Ok(InteractiveShell {
+            process: new_process(command, Vec::new()),
+            env,
+            _startup_dir: None,
+        });
+    }
+
+    let command = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+    let shell_name = std::path::Path::new(&command)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut startup_dir: Option<TempDir> = None;
+
+    let args = match shell_name.as_str() {
+        "bash" => {
+            let rc_file = new_startup_dir(&mut startup_dir)?.join("bashrc");
+            write_new_file(
+                &rc_file,
+                format!(
+                    "[ -f ~/.bashrc ] && . ~/.bashrc\nPS1=\"${} $PS1\"\n",
+                    PROMPT_MARKER_VARIABLE
+                ),
+            )?;
+            vec![
+                "--rcfile".to_string(),
+                rc_file.to_string_lossy().to_string(),
+                "-i".to_string(),
+            ]
+        }
+        "zsh" => {
+            // zsh reads its startup files from ZDOTDIR, the original one is restored by ours
+            let dir = new_startup_dir(&mut startup_dir)?;
+            let original = std::env::var("ZDOTDIR")
+                .ok()
+                .or_else(files::get_home_dir)
+                .unwrap_or_default();
+            write_new_file(
+                &dir.join(".zshrc"),
+                format!(
+                    "ZDOTDIR=\"$HISHO_ZDOTDIR\"\n[ -f \"$ZDOTDIR/.zshrc\" ] && . \"$ZDOTDIR/.zshrc\"\nPROMPT=\"${} $PROMPT\"\n",
+                    PROMPT_MARKER_VARIABLE
+                ),
+            )?;
+            env.insert("HISHO_ZDOTDIR".to_string(), original);
+            env.insert("ZDOTDIR".to_string(), dir.to_string_lossy().to_string());
+            vec!["-i".to_string()]
+        }
+        "fish" => vec![
+            "--init-command".to_string(),
+            format!(
+                "functions -q fish_prompt; and functions -c fish_prompt __hisho_fish_prompt; function fish_prompt; echo -n \"${} \"; __hisho_fish_prompt; end",
+                PROMPT_MARKER_VARIABLE
+            ),
+        ],
+        _ => {
+            env.insert("PS1".to_string(), format!("{} $ ", marker));
+            vec!["-i".to_string()]
+        }
+    };
+    Ok(InteractiveShell {
+        process: new_process(command, args),
+        env,
+        _startup_dir: startup_dir,
+    })
+}
+
+/// Create a temporary directory that only the user can access, it fails instead of reusing an existing one.
+fn new_startup_dir(startup_dir: &mut Option<TempDir>) -> io::Result<PathBuf> {
+    let dir = tempfile::Builder::new().prefix("hisho-shell-").tempdir()?;
+    let path = dir.path().to_path_buf();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700))?;
+    }
+    *startup_dir = Some(dir);
+    Ok(path)
+}
+
+/// Write a file that must not exist yet, so no existing file or symlink is followed.
+fn write_new_file(path: &Path, contents: String) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+fn new_process(command: String, args: Vec<String>) -> Process {
+    Process {
+        command,
+        args,
+        cwd: String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn startup_files_are_private_and_new() {
+        let mut startup_dir: Option<TempDir> = None;
+        let dir = new_startup_dir(&mut startup_dir).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        let rc_file = dir.join("bashrc");
+        write_new_file(&rc_file, "PS1=\"$ \"\n".to_string()).unwrap();
+        assert!(write_new_file(&rc_file, "echo injected\n".to_string()).is_err());
+        assert_eq!(std::fs::read_to_string(&rc_file).unwrap(), "PS1=\"$ \"\n");
+
+        drop(startup_dir);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,416 @@
+// This file 'sources.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::config_models::{EnvironmentSource, SourceFormat, TypedSource};
+use crate::log;
+use crate::secrets;
+
+/// The origin of a loaded file or command output, with its variables.
+pub type LoadedSource = (String, HashMap<String, String>);
+
+/// Load the variables of an entry of `sources`, for each file or command with its origin.
+///
+/// Values of .env files are interpolated with the already loaded variables, see `interpolate_dotenv`.
+/// Sources that can not be run, read or parsed are an error, with the origin in the message.
+/// Optional sources are skipped instead: missing files and failing commands silently,
+/// files and outputs that can not be parsed with an error in the log.
+pub fn load_source(
+    source: &EnvironmentSource,
+    workdir: &Path,
+    loaded: &HashMap<String, String>,
+) -> Result<Vec<LoadedSource>, String> {
+    let source = typed_source(source);
+    let skip_or_fail = |message: String, report: bool| {
+        if !source.optional {
+            return Err(message);
+        }
+        if report {
+            log::error(format!("Could not load environment source {}", message));
+        }
+        Ok(())
+    };
+
+    if let Some(process) = &source.exec {
+        let process = process.in_dir(workdir);
+        let origin = format!("command '{}'", process.command);
+        let data =
+            match secrets::run_command(&process.command, &process.args, Path::new(&process.cwd)) {
+                Ok(data) => data,
+                Err(e) => {
+                    skip_or_fail(format!("{}: {}", origin, e), false)?;
+                    return Ok(Vec::new());
+                }
+            };
+        let format = source.format.unwrap_or(SourceFormat::DotEnv);
+        return match parse_source(&data, format, &source, loaded) {
+            Ok(values) => Ok(vec![(origin, values)]),
+            Err(e) => {
+                skip_or_fail(format!("{}: {}", origin, e), true)?;
+                Ok(Vec::new())
+            }
+        };
+    }
+
+    let Some(path) = &source.path else {
+        return Err("without a path or a command".to_string());
+    };
+    let files = source_files(&EnvironmentSource::Typed(source.clone()), workdir);
+    if files.is_empty() {
+        skip_or_fail(format!("'{}': no files match the pattern", path), false)?;
+    }
+    let mut result = Vec::new();
+    for file in files {
+        let origin = file
+            .strip_prefix(workdir)
+            .unwrap_or(&file)
+            .to_string_lossy()
+            .to_string();
+        let data = match fs::read_to_string(&file) {
+            Ok(data) => data,
+            Err(e) => {
+                skip_or_fail(format!("'{}': {}", origin, e), false)?;
+                continue;
+            }
+        };
+        let format = source
+            .format
+            .unwrap_or_else(|| SourceFormat::from_path(&file));
+        match parse_source(&data, format, &source, loaded) {
+            Ok(values) => result.push((origin, values)),
+            Err(e) => skip_or_fail(format!("'{}': {}", origin, e), true)?,
+        }
+    }
+    Ok(result)
+}
+
+/// The files of an entry of `sources`, with glob patterns expanded in alphabetical order.
+///
+/// A path without a pattern is returned even if the file does not exist, commands have no files.
+pub fn source_files(source: &EnvironmentSource, workdir: &Path) -> Vec<PathBuf> {
+    let path = match source {
+        EnvironmentSource::Path(path) => path,
+        EnvironmentSource::Typed(TypedSource {
+            path: Some(path),
+            exec: None,
+            ..
+        }) => path,
+        EnvironmentSource::Typed(_) => return Vec::new(),
+    };
+    if !is_pattern(path) {
+        return vec![workdir.join(path)];
+    }
+    let pattern = workdir.join(path).to_string_lossy().to_string();
+    let mut files: Vec<PathBuf> = glob::glob(&pattern)
+        .map(|paths| paths.flatten().filter(|path| path.is_file()).collect())
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+/// Check if the path of a source is a glob pattern.
+pub fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// A plain path as a typed source of a .env file.
+fn typed_source(source: &EnvironmentSource) -> TypedSource {
+    match source {
+        EnvironmentSource::Path(path) => TypedSource {
+            path: Some(path.clone()),
+            ..TypedSource::default()
+        },
+        EnvironmentSource::Typed(source) => source.clone(),
+    }
+}
+
+/// Parse the content of a source into variables.
+///
+/// JSON, YAML and TOML documents are flattened, see `flatten`.
+fn parse_source(
+    data: &str,
+    format: SourceFormat,
+    source: &TypedSource,
+    loaded: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, String> {
+    let prefix = source.prefix.clone().unwrap_or_default();
+    let document: Value = match format {
+        SourceFormat::DotEnv => {
+            if source.select.is_some() {
+                return Err("'select' is only supported for JSON, YAML and TOML".to_string());
+            }
+            let file = dotenv_parser::parse_dotenv(data).map_err(|e| e.to_string())?;
+            return Ok(interpolate_dotenv(&file, loaded)
+                .into_iter()
+                .map(|(key, value)| (join_name(&prefix, &key), value))
+                .collect());
+        }
+        SourceFormat::Json => serde_json::from_str(data).map_err(|e| e.to_string())?,
+        SourceFormat::Yaml => serde_yaml::from_str(data).map_err(|e| e.to_string())?,
+        SourceFormat::Toml => toml::from_str(data).map_err(|e| e.to_string())?,
+    };
+    let selected = match &source.select {
+        Some(selector) => select(&document, selector)
+            .ok_or_else(|| format!("the key '{}' does not exist", selector))?,
+        None => &document,
+    };
+    let mut values: HashMap<String, String> = HashMap::new();
+    flatten(selected, prefix, &mut values);
+    if values.contains_key("") {
+        return Err("a single value can only be loaded with a 'prefix' as its name".to_string());
+    }
+    Ok(values)
+}
+
+/// Find the part of a document by a dot-separated key, like `services.web` or `servers.0`.
+fn select<'a>(document: &'a Value, selector: &str) -> Option<&'a Value> {
+    selector
+        .split('.')
+        .try_fold(document, |value, key| match value {
+            Value::Object(map) => map.get(key),
+            Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        })
+}
+
+/// Flatten a document into variables, nested keys are joined with `_` and converted to upper case.
+///
+/// Keys of arrays are their index, like `SERVERS_0_HOST`, and `null` values are skipped.
+fn flatten(value: &Value, name: String, out: &mut HashMap<String, String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten(value, join_name(&name, &variable_name(key)), out);
+            }
+        }
+        Value::Array(items) => {
+            for (index, value) in items.iter().enumerate() {
+                flatten(value, join_name(&name, &index.to_string()), out);
+            }
+        }
+        Value::Null => {}
+        Value::String(value) => {
+            out.insert(name, value.clone());
+        }
+        Value::Bool(_) | Value::Number(_) => {
+            out.insert(name, value.to_string());
+        }
+    }
+}
+
+/// Convert a key of a document into the name of a variable, like `log-level` to `LOG_LEVEL`.
+fn variable_name(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn join_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}_{}", prefix, name)
+    }
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` in the values of a `.env` file.
+///
+/// Variables are looked up in the same file first, then in the already loaded variables
+/// and at last in the system environment. Unknown variables expand to the default or to nothing.
+fn interpolate_dotenv(
+    file: &BTreeMap<String, String>,
+    loaded: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    fn lookup(
+        name: &str,
+        file: &BTreeMap<String, String>,
+        loaded: &HashMap<String, String>,
+        stack: &mut Vec<String>,
+    ) -> Option<String> {
+        match file.get(name) {
+            // a variable that references itself falls back to the loaded variables
+            Some(value) if !stack.iter().any(|n| n == name) => {
+                stack.push(name.to_string());
+                let expanded = expand(value, file, loaded, stack);
+                stack.pop();
+                Some(expanded)
+            }
+            _ => loaded
+                .get(name)
+                .cloned()
+                .or_else(|| std::env::var(name).ok()),
+        }
+    }
+
+    fn expand(
+        value: &str,
+        file: &BTreeMap<String, String>,
+        loaded: &HashMap<String, String>,
+        stack: &mut Vec<String>,
+    ) -> String {
+        let mut result = String::new();
+        let mut rest = value;
+        while let Some(start) = rest.find("${") {
+            let Some(end) = rest[start..].find('}').map(|i| start + i) else {
+                break;
+            };
+            result.push_str(&rest[..start]);
+            let expression = &rest[start + 2..end];
+            let (name, default) = match expression.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expression, None),
+            };
+            let found = lookup(name, file, loaded, stack).filter(|v| !v.is_empty());
+            result.push_str(&found.or(default.map(str::to_string)).unwrap_or_default());
+            rest = &rest[end + 1..];
+        }
+        result.push_str(rest);
+        result
+    }
+
+    file.iter()
+        .map(|(key, value)| {
+            let mut stack = vec![key.clone()];
+            (key.clone(), expand(value, file, loaded, &mut stack))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(format: SourceFormat, prefix: Option<&str>, select: Option<&str>) -> TypedSource {
+        TypedSource {
+            format: Some(format),
+            prefix: prefix.map(str::to_string),
+            select: select.map(str::to_string),
+            ..TypedSource::default()
+        }
+    }
+
+    #[test]
+    fn dotenv_values_are_interpolated() {
+        let file: BTreeMap<String, String> = [
+            ("URL", "http://${HOST}:${PORT:-8080}/${PATH_PREFIX}"),
+            ("HOST", "${LOADED_HOST}"),
+            ("SELF", "${SELF}-x"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let loaded: HashMap<String, String> = [
+            ("LOADED_HOST", "localhost"),
+            ("SELF", "loaded"),
+            ("PATH_PREFIX", "api"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let result = interpolate_dotenv(&file, &loaded);
+        assert_eq!(
+            result.get("URL"),
+            Some(&"http://localhost:8080/api".to_string())
+        );
+        assert_eq!(result.get("SELF"), Some(&"loaded-x".to_string()));
+    }
+
+    #[test]
+    fn documents_are_flattened() {
+        let yaml = "database:\n  host: localhost\n  port: 5432\n  log-level: null\nservers:\n  - web\n  - worker\n";
+        let values = parse_source(
+            yaml,
+            SourceFormat::Yaml,
+            &typed(SourceFormat::Yaml, None, None),
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(values.get("DATABASE_HOST"), Some(&"localhost".to_string()));
+        assert_eq!(values.get("DATABASE_PORT"), Some(&"5432".to_string()));
+        assert_eq!(values.get("SERVERS_1"), Some(&"worker".to_string()));
+        assert!(!values.contains_key("DATABASE_LOG_LEVEL"));
+
+        let toml = "[database]\nhost = \"db\"\nssl = true\n";
+        let values = parse_source(
+            toml,
+            SourceFormat::Toml,
+            &typed(SourceFormat::Toml, Some("DB"), Some("database")),
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            values,
+            HashMap::from([
+                ("DB_HOST".to_string(), "db".to_string()),
+                ("DB_SSL".to_string(), "true".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn selecting_missing_keys_and_single_values() {
+        let json = r#"{"services": [{"port": 80}]}"#;
+        let source = typed(SourceFormat::Json, None, Some("services.0.port"));
+        assert!(parse_source(json, SourceFormat::Json, &source, &HashMap::new()).is_err());
+        let source = typed(SourceFormat::Json, Some("PORT"), Some("services.0.port"));
+        assert_eq!(
+            parse_source(json, SourceFormat::Json, &source, &HashMap::new()).unwrap(),
+            HashMap::from([("PORT".to_string(), "80".to_string())])
+        );
+        let source = typed(SourceFormat::Json, None, Some("services.1"));
+        assert!(parse_source(json, SourceFormat::Json, &source, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn only_optional_sources_are_skipped() {
+        let workdir = std::env::temp_dir().join(format!("hisho-sources-{}", std::process::id()));
+        fs::create_dir_all(&workdir).unwrap();
+        fs::write(workdir.join("broken.json"), "{\"port\": ").unwrap();
+        let missing = TypedSource {
+            path: Some("missing.env".to_string()),
+            ..TypedSource::default()
+        };
+        let broken = TypedSource {
+            path: Some("broken.json".to_string()),
+            ..TypedSource::default()
+        };
+        let failing = TypedSource {
+            exec: Some(crate::config_models::Process {
+                command: "false".to_string(),
+                args: Vec::new(),
+                cwd: String::new(),
+            }),
+            ..TypedSource::default()
+        };
+
+        for source in [missing, broken, failing] {
+            let mut source = EnvironmentSource::Typed(source);
+            assert!(load_source(&source, &workdir, &HashMap::new()).is_err());
+            if let EnvironmentSource::Typed(typed) = &mut source {
+                typed.optional = true;
+            }
+            assert_eq!(
+                load_source(&source, &workdir, &HashMap::new()),
+                Ok(Vec::new())
+            );
+        }
+        let _ = fs::remove_dir_all(&workdir);
+    }
+}
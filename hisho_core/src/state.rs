@@ -0,0 +1,91 @@
+// This file 'state.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Local state of a project, like the remembered default environment.
+//!
+//! The state is personal and kept in `.hisho/state.ron` next to the project file,
+//! the directory should not be committed.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Name of the directory for local state, next to the project file
+pub const STATE_DIR: &str = ".hisho";
+/// Name of the state file in the `STATE_DIR`
+pub const STATE_FILE: &str = "state.ron";
+
+/// The local state of a project.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename = "State")]
+pub struct ProjectState {
+    /// Environment for commands and programs that do not choose one, set with `hisho env use`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+}
+
+/// The path of the state file of the project in `workdir`.
+pub fn state_file(workdir: &Path) -> PathBuf {
+    workdir.join(STATE_DIR).join(STATE_FILE)
+}
+
+/// Read the state of the project in `workdir`, a missing file is an empty state.
+pub fn load_state(workdir: &Path) -> Result<ProjectState, String> {
+    let path = state_file(workdir);
+    if !path.exists() {
+        return Ok(ProjectState::default());
+    }
+    let data = fs::read_to_string(&path)
+        .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+    ron::from_str(&data).map_err(|e| format!("Could not parse '{}': {}", path.display(), e))
+}
+
+/// Write the state of the project in `workdir`, creating the `STATE_DIR` if needed.
+pub fn save_state(workdir: &Path, state: &ProjectState) -> Result<(), String> {
+    let path = state_file(workdir);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Could not create '{}': {}", dir.display(), e))?;
+    }
+    let data =
+        ron::ser::to_string_pretty(state, ron::ser::PrettyConfig::default().struct_names(true))
+            .map_err(|e| e.to_string())?;
+    fs::write(&path, data + "\n")
+        .map_err(|e| format!("Could not write '{}': {}", path.display(), e))
+}
+
+/// The remembered default environment of the project in `workdir`, if any.
+///
+/// Errors reading the state are ignored, the state only holds defaults.
+pub fn default_environment(workdir: &Path) -> Option<String> {
+    load_state(workdir)
+        .ok()
+        .and_then(|state| state.environment)
+        .filter(|environment| !environment.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_is_saved_and_loaded() {
+        let workdir = std::env::temp_dir().join(format!("hisho-state-{}", std::process::id()));
+        assert_eq!(load_state(&workdir), Ok(ProjectState::default()));
+        assert_eq!(default_environment(&workdir), None);
+
+        let state = ProjectState {
+            environment: Some("staging".to_string()),
+        };
+        save_state(&workdir, &state).unwrap();
+        assert_eq!(load_state(&workdir), Ok(state));
+        assert_eq!(default_environment(&workdir), Some("staging".to_string()));
+        let _ = fs::remove_dir_all(&workdir);
+    }
+}
@@ -10,11 +10,12 @@ use liquid::Object;
 use std::collections::HashMap;
 
 use crate::config_models::Process;
+use crate::environment::EnvironmentError;
 use crate::log;
 
 type TemplateVarMap = HashMap<String, HashMap<String, String>>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct TemplateVariables(TemplateVarMap);
 
 impl TemplateVariables {
@@ -37,41 +38,132 @@ impl TemplateVariables {
 
 /// Try to render a string template with the given data for variables.
 pub fn render_string(template: String, data: &Object) -> Option<String> {
-    let tp_engine = liquid::ParserBuilder::with_stdlib().build();
-    if let Ok(engine) = &tp_engine {
-        let tp_template = engine.parse(template.as_str());
-        if let Ok(template) = tp_template {
-            let tp_value = template.render(data);
-            if let Ok(rendered_value) = tp_value {
-                return Some(rendered_value);
-            } else {
-                log::error(format!(
-                    "Failed to render template: {}",
-                    tp_value.err().unwrap()
-                ));
-            }
-        } else {
-            log::error(format!(
-                "Failed to parse template: {}",
-                tp_template.err().unwrap()
-            ));
+    match try_render_string(&template, data) {
+        Ok(rendered_value) => Some(rendered_value),
+        Err(e) => {
+            log::error(e);
+            None
+        }
+    }
+}
+
+/// Render a string template with the given data for variables, or describe why it failed.
+pub fn try_render_string(template: &str, data: &Object) -> Result<String, String> {
+    let engine = liquid::ParserBuilder::with_stdlib()
+        .build()
+        .map_err(|e| format!("Failed to create template engine: {}", e))?;
+    let template = engine
+        .parse(template)
+        .map_err(|e| format!("Failed to parse template: {}", e))?;
+    template
+        .render(data)
+        .map_err(|e| format!("Failed to render template: {}", e))
+}
+
+/// Check that a string is a valid template, without rendering it.
+pub fn check_syntax(template: &str) -> Result<(), String> {
+    let engine = liquid::ParserBuilder::with_stdlib()
+        .build()
+        .map_err(|e| e.to_string())?;
+    engine
+        .parse(template)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// A part of the value of an environment variable, only templates are rendered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValuePart {
+    /// Text that is used as it is, like the values of system variables, sources and secret providers
+    Literal(String),
+    /// A template from the project file
+    Template(String),
+}
+
+impl ValuePart {
+    /// The text of the part, without rendering it.
+    pub fn text(&self) -> &str {
+        match self {
+            ValuePart::Literal(text) | ValuePart::Template(text) => text,
         }
-    } else {
-        log::error(format!(
-            "Failed to create template engine: {}",
-            tp_engine.err().unwrap()
-        ));
     }
-    None
 }
 
-/// Render each environment variable with the environment for variables
-pub fn render_environment(env: HashMap<String, String>) -> HashMap<String, String> {
+/// Render each environment variable with the environment for variables.
+///
+/// Only the template parts of the values are rendered, literal parts are kept as they are.
+/// Variables are rendered in the order of their references, so `A: "{{env.B}}/x"` sees the
+/// rendered value of `B`. Unknown variables in `{{ }}` and cycles between variables are errors,
+/// unknown variables in tags like `{% if env.OPTIONAL %}` are empty.
+pub fn render_environment(
+    env: HashMap<String, Vec<ValuePart>>,
+) -> Result<HashMap<String, String>, EnvironmentError> {
+    let mut keys: Vec<&String> = env.keys().collect();
+    keys.sort();
+
     let mut result: HashMap<String, String> = HashMap::new();
-    for (key, value) in &env {
-        render_environment_value(key.clone(), value.clone(), &env, &mut result);
+    let mut chain: Vec<String> = Vec::new();
+    for key in keys {
+        render_environment_value(key, &env, &mut result, &mut chain)?;
+    }
+    Ok(result)
+}
+
+/// Find the variables of a scope that are referenced in a template, like `B` in `{{ env.B }}`.
+pub fn referenced_variables(template: &str, scope: &str) -> Vec<String> {
+    references(template, scope)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// The variables of a scope that are referenced in a template, and if they are printed by `{{ }}`.
+fn references(template: &str, scope: &str) -> Vec<(String, bool)> {
+    let mut variables: Vec<(String, bool)> = Vec::new();
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    let mut rest = template;
+    while let Some(open) = [rest.find("{{"), rest.find("{%")]
+        .into_iter()
+        .flatten()
+        .min()
+    {
+        let printed = rest[open..].starts_with("{{");
+        let inner = &rest[open + 2..];
+        let close = ["}}", "%}"]
+            .iter()
+            .filter_map(|end| inner.find(end))
+            .min()
+            .unwrap_or(inner.len());
+        let block = &inner[..close];
+        rest = &inner[close..];
+        for (pos, _) in block.match_indices(scope) {
+            let before = block[..pos].chars().next_back();
+            if before.is_some_and(|c| is_name_char(c) || c == '.') {
+                continue;
+            }
+            let after = &block[pos + scope.len()..];
+            let name: String = if let Some(name) = after.strip_prefix('.') {
+                name.chars().take_while(|c| is_name_char(*c)).collect()
+            } else if let Some(name) = after
+                .strip_prefix("[\"")
+                .or_else(|| after.strip_prefix("['"))
+            {
+                name.chars()
+                    .take_while(|c| *c != '"' && *c != '\'')
+                    .collect()
+            } else {
+                continue;
+            };
+            if name.is_empty() {
+                continue;
+            }
+            match variables.iter_mut().find(|(known, _)| *known == name) {
+                Some((_, known_printed)) => *known_printed |= printed,
+                None => variables.push((name, printed)),
+            }
+        }
     }
-    result
+    variables
 }
 
 /// Try to render a process with the given args for variables.
@@ -84,7 +176,7 @@ pub fn render_process(process: &Process, args: Object) -> Option<Process> {
 pub fn render_process_with_argv(
     process: &Process,
     args: Object,
-    argv: &Vec<String>,
+    argv: &[String],
 ) -> Option<Process> {
     let mut rendered_proc_args: Vec<String> = Vec::new();
     let mut proc_args = process.args.clone();
@@ -113,7 +205,7 @@ pub fn render_process_with_argv(
     }
 }
 
-fn expand_argv_label(data: &mut Vec<String>, argv: &Vec<String>) {
+fn expand_argv_label(data: &mut Vec<String>, argv: &[String]) {
     let mut positions = Vec::new();
     // find all positions that should be expanded
     for (i, string) in data.iter().enumerate() {
@@ -135,19 +227,61 @@ fn expand_argv_label(data: &mut Vec<String>, argv: &Vec<String>) {
     }
 }
 
+/// Render a variable after all the variables it references.
+///
+/// The `chain` contains the variables that are currently rendered, to detect cycles.
 fn render_environment_value(
-    key: String,
-    value: String,
-    lookup_map: &HashMap<String, String>,
-    result_map: &mut HashMap<String, String>,
-) {
+    key: &str,
+    env: &HashMap<String, Vec<ValuePart>>,
+    result: &mut HashMap<String, String>,
+    chain: &mut Vec<String>,
+) -> Result<(), EnvironmentError> {
+    if result.contains_key(key) {
+        return Ok(());
+    }
+    if let Some(pos) = chain.iter().position(|k| k == key) {
+        let mut cycle = chain[pos..].to_vec();
+        cycle.push(key.to_string());
+        return Err(EnvironmentError::VariableCycle { chain: cycle });
+    }
+
+    let parts = &env[key];
+    chain.push(key.to_string());
+    for part in parts {
+        let ValuePart::Template(template) = part else {
+            continue;
+        };
+        for (reference, printed) in references(template, "env") {
+            if env.contains_key(&reference) {
+                render_environment_value(&reference, env, result, chain)?;
+            } else if printed {
+                return Err(EnvironmentError::MissingVariable {
+                    key: key.to_string(),
+                    missing: reference,
+                });
+            }
+        }
+    }
+    chain.pop();
+
     let mut vars = TemplateVariables::new();
-    vars.insert("env", lookup_map.clone());
-    if let Some(rendered_value) = render_string(value.clone(), &vars.as_value()) {
-        result_map.insert(key.clone(), rendered_value);
-    } else {
-        result_map.insert(key.clone(), value.clone());
+    vars.insert("env", result.clone());
+    let mut rendered = String::new();
+    for part in parts {
+        match part {
+            ValuePart::Literal(text) => rendered.push_str(text),
+            ValuePart::Template(template) => {
+                rendered.push_str(&try_render_string(template, &vars.as_value()).map_err(
+                    |message| EnvironmentError::Render {
+                        key: key.to_string(),
+                        message,
+                    },
+                )?)
+            }
+        }
     }
+    result.insert(key.to_string(), rendered);
+    Ok(())
 }
 
 #[cfg(test)]
@@ -175,18 +309,23 @@ mod tests {
         assert_eq!(render_string(template, &data), None);
     }
 
+    fn templates(values: &[(&str, &str)]) -> HashMap<String, Vec<ValuePart>> {
+        values
+            .iter()
+            .map(|(k, v)| (k.to_string(), vec![ValuePart::Template(v.to_string())]))
+            .collect()
+    }
+
     #[test]
     fn render_valid_environment_template() {
-        let mut env = HashMap::new();
-        env.insert("hello_name".to_string(), "Hello, {{env.name}}!".to_string());
-        env.insert("name".to_string(), "John".to_string());
-        env.insert("world".to_string(), "world".to_string());
-        env.insert(
-            "hello_world".to_string(),
-            "Hello, {{env.world}}!".to_string(),
-        );
+        let env = templates(&[
+            ("hello_name", "Hello, {{env.name}}!"),
+            ("name", "John"),
+            ("world", "world"),
+            ("hello_world", "Hello, {{env.world}}!"),
+        ]);
 
-        let rendered = render_environment(env);
+        let rendered = render_environment(env).unwrap();
         assert_eq!(
             rendered.get("hello_name"),
             Some(&"Hello, John!".to_string())
@@ -197,6 +336,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn literal_parts_and_optional_variables() {
+        let mut env = templates(&[
+            ("FLAG", "{% if env.OPTIONAL %}on{% else %}off{% endif %}"),
+            ("URL", "db://app:{{env.PASSWORD}}@db"),
+        ]);
+        env.insert(
+            "PASSWORD".to_string(),
+            vec![ValuePart::Literal("pa{{ss}}{%wd".to_string())],
+        );
+        let rendered = render_environment(env).unwrap();
+        assert_eq!(rendered["FLAG"], "off");
+        assert_eq!(rendered["PASSWORD"], "pa{{ss}}{%wd");
+        assert_eq!(rendered["URL"], "db://app:pa{{ss}}{%wd@db");
+
+        let env = templates(&[("URL", "db://{{env.OPTIONAL}}")]);
+        assert_eq!(
+            render_environment(env).unwrap_err(),
+            EnvironmentError::MissingVariable {
+                key: "URL".to_string(),
+                missing: "OPTIONAL".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn find_referenced_variables() {
+        assert_eq!(
+            referenced_variables(
+                "{{ env.A }}/{{env['B'] | upcase}}{% if env.C %}x{% endif %} env.D {{ myenv.E }}",
+                "env"
+            ),
+            vec!["A", "B", "C"]
+        );
+    }
+
     #[test]
     fn render_valid_process_template() {
         let mut env = HashMap::new();
@@ -216,7 +391,7 @@ mod tests {
             assert_eq!(rendered_process.command, "echo".to_string());
             assert_eq!(rendered_process.args[0], "Hello, John!".to_string());
         } else {
-            assert!(false);
+            panic!("process should have been rendered");
         }
     }
 
@@ -240,7 +415,7 @@ mod tests {
         if let Some(rendered_process) = rendered {
             assert_eq!(rendered_process.command, "/usr/local/bin/echo".to_string());
         } else {
-            assert!(false);
+            panic!("process should have been rendered");
         }
     }
 }
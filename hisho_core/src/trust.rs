@@ -0,0 +1,187 @@
+// This file 'trust.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Projects the user allowed to be loaded automatically by the shell hook, like `direnv allow`.
+//!
+//! Environments can run commands, so the hook only loads projects that were allowed with `hisho allow`.
+//! A project is allowed by the canonical path of its project file, together with a hash of the
+//! project file, its includes and the personal `hisho.local.ron`, any change to them revokes the permission.
+//! The allowed projects are kept in `~/.config/hisho/allowed.ron`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::overrides::{self, USER_CONFIG_DIR};
+use crate::{files, project};
+
+/// Name of the file with the allowed projects, in the user-level configuration directory
+pub const ALLOWED_FILE: &str = "allowed.ron";
+
+/// The allowed project files with the hash of their files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename = "Allowed")]
+struct AllowedProjects {
+    #[serde(default)]
+    projects: BTreeMap<String, String>,
+}
+
+/// The path of the file with the allowed projects, like `~/.config/hisho/allowed.ron`.
+pub fn allowed_file() -> Option<PathBuf> {
+    let home_dir = files::get_home_dir()?;
+    Some(
+        files::string_to_path(&home_dir)
+            .join(USER_CONFIG_DIR)
+            .join(ALLOWED_FILE),
+    )
+}
+
+/// The files a project is loaded from: the project file, its includes and the personal override file.
+///
+/// Only the includes are followed, the files are not merged or validated.
+pub fn trusted_files(project_file: &Path) -> Result<Vec<PathBuf>, String> {
+    let project_file = fs::canonicalize(project_file)
+        .map_err(|e| format!("Could not find '{}': {}", project_file.display(), e))?;
+    let mut found: Vec<PathBuf> = Vec::new();
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    let mut pending: Vec<PathBuf> = vec![project_file.clone()];
+    while let Some(path) = pending.pop() {
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        let included = project::read_project_file(&path).map_err(|e| e.to_string())?;
+        let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        for include in included.includes.iter().rev() {
+            let include_path =
+                files::resolve_path(base_dir.join(include).to_string_lossy().to_string())
+                    .map_err(|e| format!("Could not find include '{}': {}", include, e))?;
+            pending.push(include_path);
+        }
+        found.push(path);
+    }
+    found.extend(overrides::find_local_override(&project_file));
+    Ok(found)
+}
+
+/// The SHA-256 hash of the files of a project, see `trusted_files`.
+pub fn project_hash(project_file: &Path) -> Result<String, String> {
+    let mut hasher = Sha256::new();
+    for path in trusted_files(project_file)? {
+        let data =
+            fs::read(&path).map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update((data.len() as u64).to_le_bytes());
+        hasher.update(&data);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Check if the project file is allowed and none of its files changed since.
+pub fn is_allowed(project_file: &Path) -> bool {
+    allowed_file().is_some_and(|store| is_allowed_in(&store, project_file))
+}
+
+/// Allow the project file to be loaded by the shell hook, with its current files.
+pub fn allow(project_file: &Path) -> Result<(), String> {
+    let store = allowed_file().ok_or("Could not find the home directory")?;
+    let hash = project_hash(project_file)?;
+    update_store(&store, project_file, Some(hash))
+}
+
+/// Revoke the permission of the project file to be loaded by the shell hook.
+pub fn deny(project_file: &Path) -> Result<(), String> {
+    let store = allowed_file().ok_or("Could not find the home directory")?;
+    update_store(&store, project_file, None)
+}
+
+fn is_allowed_in(store: &Path, project_file: &Path) -> bool {
+    let Ok(allowed) = load_store(store) else {
+        return false;
+    };
+    let Some(hash) = allowed.projects.get(&key(project_file)) else {
+        return false;
+    };
+    project_hash(project_file).is_ok_and(|current| current == *hash)
+}
+
+fn update_store(store: &Path, project_file: &Path, hash: Option<String>) -> Result<(), String> {
+    let mut allowed = load_store(store)?;
+    match hash {
+        Some(hash) => allowed.projects.insert(key(project_file), hash),
+        None => allowed.projects.remove(&key(project_file)),
+    };
+    if let Some(dir) = store.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Could not create '{}': {}", dir.display(), e))?;
+    }
+    let data = ron::ser::to_string_pretty(
+        &allowed,
+        ron::ser::PrettyConfig::default().struct_names(true),
+    )
+    .map_err(|e| e.to_string())?;
+    fs::write(store, data + "\n")
+        .map_err(|e| format!("Could not write '{}': {}", store.display(), e))
+}
+
+fn load_store(store: &Path) -> Result<AllowedProjects, String> {
+    if !store.exists() {
+        return Ok(AllowedProjects::default());
+    }
+    let data = fs::read_to_string(store)
+        .map_err(|e| format!("Could not read '{}': {}", store.display(), e))?;
+    ron::from_str(&data).map_err(|e| format!("Could not parse '{}': {}", store.display(), e))
+}
+
+/// Projects are allowed by the canonical path of their project file.
+fn key(project_file: &Path) -> String {
+    fs::canonicalize(project_file)
+        .unwrap_or_else(|_| project_file.to_path_buf())
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_to_includes_revoke_the_permission() {
+        let workdir = std::env::temp_dir().join(format!("hisho-trust-{}", std::process::id()));
+        fs::create_dir_all(&workdir).unwrap();
+        let project_file = workdir.join("hisho.ron");
+        let store = workdir.join(ALLOWED_FILE);
+        fs::write(
+            &project_file,
+            "Project(name: \"test\", includes: [\"envs.ron\"])",
+        )
+        .unwrap();
+        fs::write(workdir.join("envs.ron"), "Project(name: \"envs\")").unwrap();
+
+        assert!(!is_allowed_in(&store, &project_file));
+        let hash = project_hash(&project_file).unwrap();
+        update_store(&store, &project_file, Some(hash)).unwrap();
+        assert!(is_allowed_in(&store, &project_file));
+
+        fs::write(
+            workdir.join("envs.ron"),
+            "Project(name: \"envs\", environments: [Environment(name: \"dev\")])",
+        )
+        .unwrap();
+        assert!(!is_allowed_in(&store, &project_file));
+
+        let hash = project_hash(&project_file).unwrap();
+        update_store(&store, &project_file, Some(hash)).unwrap();
+        update_store(&store, &project_file, None).unwrap();
+        assert!(!is_allowed_in(&store, &project_file));
+        let _ = fs::remove_dir_all(&workdir);
+    }
+}
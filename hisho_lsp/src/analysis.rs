@@ -0,0 +1,324 @@
+// This file 'analysis.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Text analysis of project files, independent of their format.
+//!
+//! The project files are not parsed with positions, instead the fields are found by their keys.
+//! This works for RON, TOML, YAML and JSON alike, as long as the keys are written out.
+
+use lsp_types::Position;
+
+/// Fields that reference other entities, with the kind of the entity and whether it is a list
+const REFERENCE_FIELDS: [(&str, &str, bool); 4] = [
+    ("depends_on_build", "build step", true),
+    ("depends_on", "build step", true),
+    ("inherits", "environment", true),
+    ("environment", "environment", false),
+];
+
+/// Markers that start the definition of an entity, by the kind of the entity
+///
+/// Struct names are used by RON, array tables by TOML and keys by YAML and JSON.
+const KIND_MARKERS: [(&str, &str); 15] = [
+    ("environment", "Environment("),
+    ("environment", "[[environments]]"),
+    ("environment", "environments"),
+    ("container", "Container("),
+    ("container", "[[containers]]"),
+    ("container", "containers"),
+    ("build step", "BuildStep("),
+    ("build step", "[[build]]"),
+    ("build step", "build"),
+    ("service", "Service("),
+    ("service", "[[services]]"),
+    ("service", "services"),
+    ("command", "Command("),
+    ("command", "[[commands]]"),
+    ("command", "commands"),
+];
+
+/// Convert a byte offset into a LSP position, the character is counted in UTF-16 code units.
+pub fn offset_to_position(text: &str, offset: usize) -> Position {
+    let offset = offset.min(text.len());
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let character = before[line_start..].encode_utf16().count();
+    Position::new(line as u32, character as u32)
+}
+
+/// Convert a LSP position into a byte offset, positions past the end are clamped.
+pub fn position_to_offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let line_end = text[line_start..]
+        .find('\n')
+        .map(|i| line_start + i)
+        .unwrap_or(text.len());
+    let mut units = 0;
+    for (i, c) in text[line_start..line_end].char_indices() {
+        if units >= position.character as usize {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    line_end
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    !matches!(
+        c,
+        '"' | '\'' | ',' | '[' | ']' | '(' | ')' | '{' | '}' | ' ' | '\t' | '\r' | '\n'
+    )
+}
+
+/// Find the byte range of the name, like `web:build`, around the offset.
+pub fn name_range_at(text: &str, offset: usize) -> (usize, usize) {
+    let start = text[..offset]
+        .char_indices()
+        .rev()
+        .find(|(_, c)| !is_name_char(*c))
+        .map(|(i, c)| i + c.len_utf8())
+        .unwrap_or(0);
+    let end = text[offset..]
+        .char_indices()
+        .find(|(_, c)| !is_name_char(*c))
+        .map(|(i, _)| offset + i)
+        .unwrap_or(text.len());
+    (start, end)
+}
+
+/// Find the key at `pos`, returns the offset after its separator `:` or `=`.
+///
+/// The key must not be part of a longer word and may be quoted, like in JSON.
+fn key_separator_end(text: &str, pos: usize, key: &str) -> Option<usize> {
+    if text[..pos].chars().next_back().is_some_and(is_word_char) {
+        return None;
+    }
+    let mut rest = &text[pos + key.len()..];
+    rest = rest.strip_prefix('"').unwrap_or(rest);
+    let trimmed = rest.trim_start_matches([' ', '\t']);
+    let separator = trimmed.chars().next()?;
+    if separator != ':' && separator != '=' {
+        return None;
+    }
+    Some(text.len() - trimmed.len() + 1)
+}
+
+/// Find the last occurrence of a key before the end of the text.
+fn last_key(text: &str, key: &str) -> Option<(usize, usize)> {
+    text.rmatch_indices(key)
+        .find_map(|(pos, _)| key_separator_end(text, pos, key).map(|end| (pos, end)))
+}
+
+/// Remove all quoted strings from the text, an unterminated string is removed until the end.
+fn without_strings(text: &str) -> String {
+    let mut result = String::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Determine the kind of entity the field at the offset references, like `build step` in `depends_on`.
+pub fn reference_kind_at(text: &str, offset: usize) -> Option<&'static str> {
+    let before = &text[..offset];
+    let (_, separator_end, kind, is_list) = REFERENCE_FIELDS
+        .iter()
+        .filter_map(|(key, kind, is_list)| {
+            last_key(before, key).map(|(pos, end)| (pos, end, *kind, *is_list))
+        })
+        .max_by_key(|(pos, ..)| *pos)?;
+
+    let value = without_strings(&before[separator_end..]);
+    let closed = value.contains([']', '(', ')', '{', '}', '='])
+        || value.contains(": ")
+        || value.contains(":\n");
+    if closed || (!is_list && value.contains([',', '\n', '['])) {
+        return None;
+    }
+    Some(kind)
+}
+
+/// Determine the kind of entity whose `name` is defined at the offset.
+pub fn definition_kind_at(text: &str, offset: usize) -> Option<&'static str> {
+    let (start, _) = name_range_at(text, offset);
+    if !is_name_value(text, start) {
+        return None;
+    }
+    kind_before(text, start)
+}
+
+/// Check if the value at `start` is the value of a `name` key.
+fn is_name_value(text: &str, start: usize) -> bool {
+    let before = text[..start]
+        .trim_end_matches('"')
+        .trim_end_matches([' ', '\t']);
+    let Some(before) = before
+        .strip_suffix(':')
+        .or_else(|| before.strip_suffix('='))
+    else {
+        return false;
+    };
+    let before = before.trim_end_matches([' ', '\t']).trim_end_matches('"');
+    before.ends_with("name")
+        && !before[..before.len() - 4]
+            .chars()
+            .next_back()
+            .is_some_and(is_word_char)
+}
+
+/// The kind of the entity whose definition contains the offset.
+fn kind_before(text: &str, offset: usize) -> Option<&'static str> {
+    let before = &text[..offset];
+    KIND_MARKERS
+        .iter()
+        .filter_map(|(kind, marker)| {
+            let pos = if marker.ends_with(['(', ']']) {
+                before
+                    .rmatch_indices(marker)
+                    .map(|(pos, _)| pos)
+                    .find(|pos| !before[..*pos].chars().next_back().is_some_and(is_word_char))
+            } else {
+                last_key(before, marker).map(|(pos, _)| pos)
+            };
+            pos.map(|pos| (pos, *kind))
+        })
+        .max_by_key(|(pos, _)| *pos)
+        .map(|(_, kind)| kind)
+}
+
+/// Find the byte range of the name in the definition of an entity.
+pub fn find_definition(text: &str, kind: &str, name: &str) -> Option<(usize, usize)> {
+    if name.is_empty() {
+        return None;
+    }
+    text.match_indices(name)
+        .map(|(pos, _)| (pos, pos + name.len()))
+        .filter(|(start, end)| name_range_at(text, *start) == (*start, *end))
+        .filter(|(start, _)| is_name_value(text, *start))
+        .find(|(start, _)| kind_before(text, *start) == Some(kind))
+}
+
+/// Find the text of an unfinished template, like `git.comm` in `{{ git.comm`.
+///
+/// Returns the byte offset where the variable starts.
+pub fn template_variable_at(text: &str, offset: usize) -> Option<usize> {
+    let before = &text[..offset];
+    let open = before.rfind("{{")?;
+    let inside = &before[open + 2..];
+    if inside.contains("}}") || inside.contains(['"', '\n']) {
+        return None;
+    }
+    Some(offset - inside.trim_start().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RON: &str = r#"Project(
+    name: "test",
+    environments: [
+        Environment(name: "dev", inherits: ["base"]),
+        Environment(name: "base"),
+    ],
+    build: [
+        BuildStep(name: "debug", shell: [], depends_on: ["web:build", ]),
+    ],
+    commands: [
+        Command(name: "dev", environment: "dev", depends_on_build: ["debug"]),
+    ],
+)"#;
+
+    fn offset_of(text: &str, needle: &str) -> usize {
+        text.find(needle).unwrap() + needle.len()
+    }
+
+    #[test]
+    fn positions_round_trip() {
+        let text = "a\näb\nc";
+        let position = offset_to_position(text, 5);
+        assert_eq!(position, Position::new(1, 2));
+        assert_eq!(position_to_offset(text, position), 5);
+        assert_eq!(position_to_offset(text, Position::new(9, 0)), text.len());
+    }
+
+    #[test]
+    fn references_are_found_by_their_field() {
+        assert_eq!(
+            reference_kind_at(RON, offset_of(RON, "inherits: [\"ba")),
+            Some("environment")
+        );
+        assert_eq!(
+            reference_kind_at(RON, offset_of(RON, "\"web:build\", ")),
+            Some("build step")
+        );
+        assert_eq!(
+            reference_kind_at(RON, offset_of(RON, "environment: \"de")),
+            Some("environment")
+        );
+        assert_eq!(
+            reference_kind_at(RON, offset_of(RON, "depends_on_build: [\"deb")),
+            Some("build step")
+        );
+        assert_eq!(reference_kind_at(RON, offset_of(RON, "shell: [")), None);
+
+        let yaml = "commands:\n- name: run\n  depends_on_build:\n  - deb";
+        assert_eq!(reference_kind_at(yaml, yaml.len()), Some("build step"));
+        let yaml = "commands:\n- name: run\n  environment: dev\n  shell: ";
+        assert_eq!(reference_kind_at(yaml, yaml.len()), None);
+    }
+
+    #[test]
+    fn definitions_are_found_by_kind() {
+        let (start, end) = find_definition(RON, "command", "dev").unwrap();
+        assert_eq!(&RON[start..end], "dev");
+        assert!(start > RON.find("Command(").unwrap());
+
+        let (start, _) = find_definition(RON, "environment", "dev").unwrap();
+        assert!(start < RON.find("Command(").unwrap());
+        assert_eq!(definition_kind_at(RON, start + 1), Some("environment"));
+        assert!(find_definition(RON, "build step", "dev").is_none());
+
+        let toml = "name = \"test\"\n[[build]]\nname = \"debug\"\nshell = []\n[[commands]]\nname = \"run\"\ndepends_on_build = [\"debug\"]\n";
+        let (start, _) = find_definition(toml, "build step", "debug").unwrap();
+        assert_eq!(start, toml.find("debug").unwrap());
+    }
+
+    #[test]
+    fn template_variables_are_found() {
+        let text = "args: [\"{{ git.comm";
+        assert_eq!(template_variable_at(text, text.len()), Some(text.len() - 8));
+        let text = "args: [\"{{ git.commit_sha }}\", \"";
+        assert_eq!(template_variable_at(text, text.len()), None);
+    }
+}
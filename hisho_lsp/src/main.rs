@@ -0,0 +1,67 @@
+// This file 'main.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+mod analysis;
+mod server;
+
+use std::error::Error;
+
+use lsp_server::{Connection, Message};
+use lsp_types::{
+    CompletionOptions, HoverProviderCapability, OneOf, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncOptions,
+};
+
+use crate::server::Server;
+
+fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    // stdout is used for the protocol, so nothing may be printed to it
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = serde_json::to_value(ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Options(
+            TextDocumentSyncOptions {
+                open_close: Some(true),
+                change: Some(TextDocumentSyncKind::FULL),
+                save: Some(true.into()),
+                ..Default::default()
+            },
+        )),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec!["\"".to_string(), ".".to_string(), ":".to_string()]),
+            ..Default::default()
+        }),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        ..Default::default()
+    })?;
+    connection.initialize(capabilities)?;
+
+    let mut server = Server::default();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    break;
+                }
+                let response = server.handle_request(request);
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                for reply in server.handle_notification(notification) {
+                    connection.sender.send(Message::Notification(reply))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+    // the writer thread only stops once the connection is dropped
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
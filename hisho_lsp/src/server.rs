@@ -0,0 +1,527 @@
+// This file 'server.rs' is part of the 'hisho' project.
+//
+// Copyright 2023 Thomas Obernosterer (https://atjon.tv).
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use hisho_core::check::{Problem, Severity};
+use hisho_core::config_models::{split_reference, Environment, Project};
+use hisho_core::overrides::{EntityOrigins, LOCAL_OVERRIDE_FILE_STEM};
+use hisho_core::project::{ProjectError, ProjectLoader};
+use hisho_core::{build_tool, check, environment, files, git, overrides, project};
+use lsp_server::{ErrorCode, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+    Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as _};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionParams, CompletionResponse, Diagnostic,
+    DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents,
+    HoverParams, Location, MarkupContent, MarkupKind, Position, PublishDiagnosticsParams, Range,
+    TextEdit, Url,
+};
+
+use crate::analysis;
+
+/// An open project file and the project it belongs to.
+struct Document {
+    path: PathBuf,
+    text: String,
+    /// The project loaded from disk, with includes, overrides and members
+    project: Option<Project>,
+    origins: EntityOrigins,
+    /// Error while loading the project from disk, other than a parse error of this file
+    load_error: Option<String>,
+    /// Problems of the static validation, updated when the file is opened or saved
+    problems: Vec<Problem>,
+    /// Override files like `hisho.local.ron` are only partial projects
+    is_override: bool,
+}
+
+impl Document {
+    fn new(path: PathBuf, text: String) -> Self {
+        let is_override = path
+            .file_stem()
+            .is_some_and(|stem| stem == LOCAL_OVERRIDE_FILE_STEM)
+            || overrides::find_user_config().as_deref() == Some(path.as_path());
+        Document {
+            path,
+            text,
+            project: None,
+            origins: EntityOrigins::new(),
+            load_error: None,
+            problems: Vec::new(),
+            is_override,
+        }
+    }
+
+    /// Load the project from disk and validate it.
+    fn refresh(&mut self) {
+        let loader = if self.is_override {
+            // overrides belong to the project next to them
+            let dir = self.path.parent().unwrap_or(Path::new("."));
+            match project::discover_project_file(dir) {
+                Some(project_file) => ProjectLoader::new(project_file.to_string_lossy().as_ref()),
+                None => return,
+            }
+        } else {
+            ProjectLoader::new(self.path.to_string_lossy().as_ref())
+        };
+        match loader.load_with_origins() {
+            Ok((project, origins)) => {
+                self.problems = if self.is_override {
+                    Vec::new()
+                } else {
+                    check::check_project(&project)
+                        .into_iter()
+                        .filter(|problem| problem.project == project.name)
+                        .collect()
+                };
+                self.project = Some(project);
+                self.origins = origins;
+                self.load_error = None;
+            }
+            // parse errors of this file are reported from the text in the editor
+            Err(ProjectError::Parse(diagnostic)) if diagnostic.path == self.path => {
+                self.load_error = None;
+            }
+            Err(e) => self.load_error = Some(e.to_string()),
+        }
+    }
+
+    fn diagnostics(&self) -> Vec<Diagnostic> {
+        let parsed = if self.is_override {
+            project::parse_project_override(&self.path, &self.text).map(|_| ())
+        } else {
+            project::parse_project(&self.path, &self.text).map(|_| ())
+        };
+        if let Err(e) = parsed {
+            let (range, message) = match e {
+                ProjectError::Parse(diagnostic) => {
+                    let position = Position::new(
+                        diagnostic.line.saturating_sub(1) as u32,
+                        diagnostic.column.saturating_sub(1) as u32,
+                    );
+                    (Range::new(position, position), diagnostic.message)
+                }
+                e => (Range::default(), e.to_string()),
+            };
+            return vec![new_diagnostic(range, DiagnosticSeverity::ERROR, message)];
+        }
+
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
+        if let Some(load_error) = &self.load_error {
+            diagnostics.push(new_diagnostic(
+                Range::default(),
+                DiagnosticSeverity::ERROR,
+                load_error.clone(),
+            ));
+        }
+        for problem in &self.problems {
+            let range = analysis::find_definition(&self.text, problem.kind, &problem.name)
+                .map(|range| self.range(range))
+                .unwrap_or_default();
+            let severity = match problem.severity {
+                Severity::Error => DiagnosticSeverity::ERROR,
+                Severity::Warning => DiagnosticSeverity::WARNING,
+            };
+            diagnostics.push(new_diagnostic(range, severity, problem.to_string()));
+        }
+        diagnostics
+    }
+
+    fn range(&self, (start, end): (usize, usize)) -> Range {
+        Range::new(
+            analysis::offset_to_position(&self.text, start),
+            analysis::offset_to_position(&self.text, end),
+        )
+    }
+
+    /// The project of this document, the workspace itself or one of its members.
+    fn own_project(&self) -> Option<&Project> {
+        let workspace = self.project.as_ref()?;
+        let file = self.path.to_string_lossy();
+        Some(
+            workspace
+                .member_projects
+                .iter()
+                .find(|member| member.file == file)
+                .unwrap_or(workspace),
+        )
+    }
+
+    /// The kind and name of the entity at the offset, either a reference or a definition.
+    fn entity_at(&self, offset: usize) -> Option<(&'static str, String)> {
+        let (start, end) = analysis::name_range_at(&self.text, offset);
+        let name = self.text[start..end].to_string();
+        if name.is_empty() {
+            return None;
+        }
+        analysis::reference_kind_at(&self.text, start)
+            .or_else(|| analysis::definition_kind_at(&self.text, start))
+            .map(|kind| (kind, name))
+    }
+
+    fn completion(&self, offset: usize) -> Vec<CompletionItem> {
+        let Some(project) = self.own_project() else {
+            return Vec::new();
+        };
+        let workspace = self.project.as_ref().unwrap_or(project);
+
+        if let Some(start) = analysis::template_variable_at(&self.text, offset) {
+            let range = self.range((start, offset));
+            return template_variables(project)
+                .into_iter()
+                .map(|(label, detail)| CompletionItem {
+                    text_edit: Some(TextEdit::new(range, label.clone()).into()),
+                    label,
+                    detail: Some(detail),
+                    kind: Some(CompletionItemKind::VARIABLE),
+                    ..Default::default()
+                })
+                .collect();
+        }
+
+        let Some(kind) = analysis::reference_kind_at(&self.text, offset) else {
+            return Vec::new();
+        };
+        let (start, _) = analysis::name_range_at(&self.text, offset);
+        let range = self.range((start, offset));
+        let mut names: Vec<(String, String)> = Vec::new();
+        match kind {
+            "build step" => {
+                for step in &project.build {
+                    names.push((step.name.clone(), "build step".to_string()));
+                }
+                for other in std::iter::once(workspace).chain(workspace.member_projects.iter()) {
+                    if std::ptr::eq(other, project) {
+                        continue;
+                    }
+                    for step in &other.build {
+                        names.push((
+                            format!("{}:{}", other.name, step.name),
+                            format!("build step of project '{}'", other.name),
+                        ));
+                    }
+                }
+            }
+            _ => {
+                for env in &project.environments {
+                    names.push((env.name.clone(), "environment".to_string()));
+                }
+            }
+        }
+        names
+            .into_iter()
+            .map(|(label, detail)| CompletionItem {
+                text_edit: Some(TextEdit::new(range, label.clone()).into()),
+                label,
+                detail: Some(detail),
+                kind: Some(CompletionItemKind::REFERENCE),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn definition(&self, offset: usize, documents: &HashMap<Url, Document>) -> Option<Location> {
+        let (kind, reference) = self.entity_at(offset)?;
+        let workspace = self.project.as_ref()?;
+        let project = self.own_project()?;
+        let (project_name, name) = split_reference(&reference);
+        let target = if project_name.is_empty() {
+            project
+        } else {
+            workspace.resolve_project(project_name)?
+        };
+
+        // the first origin is where the entity was defined, includes have their own file
+        let origins = if std::ptr::eq(target, workspace) {
+            self.origins.clone()
+        } else {
+            ProjectLoader::new(&target.file)
+                .load_with_origins()
+                .map(|(_, origins)| origins)
+                .unwrap_or_default()
+        };
+        let path = origins
+            .get(&(kind, name.to_string()))
+            .and_then(|paths| paths.first().cloned())
+            .unwrap_or_else(|| files::string_to_path(&target.file));
+
+        let uri = Url::from_file_path(&path).ok()?;
+        let text = match documents.get(&uri) {
+            Some(document) => document.text.clone(),
+            None => fs::read_to_string(&path).ok()?,
+        };
+        let (start, end) = analysis::find_definition(&text, kind, name)?;
+        Some(Location::new(
+            uri,
+            Range::new(
+                analysis::offset_to_position(&text, start),
+                analysis::offset_to_position(&text, end),
+            ),
+        ))
+    }
+
+    fn hover(&self, offset: usize) -> Option<Hover> {
+        let (kind, reference) = self.entity_at(offset)?;
+        let workspace = self.project.as_ref()?;
+        let project = self.own_project()?;
+
+        let text = match kind {
+            "build step" => {
+                let order = build_tool::resolve_build_order(
+                    workspace,
+                    project,
+                    std::slice::from_ref(&reference),
+                )
+                .ok()?;
+                format!("**Build order** of `{}`\n\n{}", reference, numbered(&order))
+            }
+            "environment" => {
+                // commands and secret providers are never run for hovers, only static values are shown
+                let (env, skipped) = environment::fetch_static_environment(
+                    &reference,
+                    &project.environments,
+                    files::string_to_path(&project.workdir).as_path(),
+                )
+                .ok()?;
+                let mut text = format!("**Environment** `{}`\n\n{}", reference, values_table(&env));
+                if !skipped.is_empty() {
+                    text += &format!(
+                        "\n**Not resolved**\n\n{}",
+                        skipped
+                            .iter()
+                            .map(|source| format!("* {}", source))
+                            .collect::<Vec<String>>()
+                            .join("\n")
+                    );
+                }
+                text
+            }
+            "command" => {
+                let cmd = project.commands.iter().find(|c| c.name == reference)?;
+                let mut text = format!("**Command** `{}`", cmd.name);
+                if !cmd.environment.is_empty() {
+                    text += &format!("\n\nEnvironment: `{}`", cmd.environment);
+                }
+                if !cmd.depends_on_build.is_empty() {
+                    let order =
+                        build_tool::resolve_build_order(workspace, project, &cmd.depends_on_build)
+                            .ok()?;
+                    text += &format!("\n\n**Build order**\n\n{}", numbered(&order));
+                }
+                text
+            }
+            _ => return None,
+        };
+
+        let (start, end) = analysis::name_range_at(&self.text, offset);
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text,
+            }),
+            range: Some(self.range((start, end))),
+        })
+    }
+}
+
+fn new_diagnostic(range: Range, severity: DiagnosticSeverity, message: String) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(severity),
+        source: Some("hisho".to_string()),
+        message,
+        ..Default::default()
+    }
+}
+
+fn numbered(names: &[String]) -> String {
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| format!("{}. `{}`", i + 1, name))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// The variables of the environment as a Markdown table, sensitive values are masked.
+fn values_table(env: &Environment) -> String {
+    let sorted: BTreeMap<&String, &String> = env.values.iter().collect();
+    let mut table = "| Key | Value |\n|-----|-------|\n".to_string();
+    for key in sorted.keys() {
+        let value = environment::shown_value(env, key);
+        table += &format!("| `{}` | `{}` |\n", key, value.replace('|', "\\|"));
+    }
+    table
+}
+
+/// All variables that can be used in templates of the project, with a description.
+fn template_variables(project: &Project) -> Vec<(String, String)> {
+    let workdir = files::string_to_path(&project.workdir);
+    let mut variables: BTreeMap<String, String> = BTreeMap::new();
+    for (key, value) in git::fetch_repo_vars(workdir.as_path()) {
+        variables.insert(format!("git.{}", key), value);
+    }
+    for env in &project.environments {
+        if let Ok((resolved, _)) = environment::fetch_static_environment(
+            &env.name,
+            &project.environments,
+            workdir.as_path(),
+        ) {
+            for key in resolved.values.keys() {
+                variables
+                    .entry(format!("env.{}", key))
+                    .or_insert_with(|| format!("from environment '{}'", env.name));
+            }
+        }
+    }
+    variables.insert(
+        "build.name".to_string(),
+        "name of the build step".to_string(),
+    );
+    variables.insert(
+        "build.input_files".to_string(),
+        "files matching the input_files of the build step".to_string(),
+    );
+    variables.into_iter().collect()
+}
+
+/// The language server state, all open documents.
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<Url, Document>,
+}
+
+impl Server {
+    /// Handle a request and create the response to it.
+    pub fn handle_request(&mut self, request: Request) -> Response {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            Completion::METHOD => parse(request).map(|params: CompletionParams| {
+                let position = params.text_document_position;
+                let items = self
+                    .document(&position.text_document.uri, position.position)
+                    .map(|(document, offset)| document.completion(offset))
+                    .unwrap_or_default();
+                serde_json::to_value(CompletionResponse::Array(items))
+            }),
+            GotoDefinition::METHOD => parse(request).map(|params: GotoDefinitionParams| {
+                let position = params.text_document_position_params;
+                let location = self
+                    .document(&position.text_document.uri, position.position)
+                    .and_then(|(document, offset)| document.definition(offset, &self.documents));
+                serde_json::to_value(location.map(GotoDefinitionResponse::Scalar))
+            }),
+            HoverRequest::METHOD => parse(request).map(|params: HoverParams| {
+                let position = params.text_document_position_params;
+                let hover = self
+                    .document(&position.text_document.uri, position.position)
+                    .and_then(|(document, offset)| document.hover(offset));
+                serde_json::to_value(hover)
+            }),
+            method => {
+                return Response::new_err(
+                    id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("Unknown method '{}'", method),
+                )
+            }
+        };
+        match result {
+            Ok(Ok(value)) => Response::new_ok(id, value),
+            Ok(Err(e)) | Err(e) => {
+                Response::new_err(id, ErrorCode::InvalidParams as i32, e.to_string())
+            }
+        }
+    }
+
+    /// Handle a notification, returns the notifications to send back.
+    pub fn handle_notification(&mut self, notification: Notification) -> Vec<Notification> {
+        let uri = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let Ok(params) =
+                    serde_json::from_value::<DidOpenTextDocumentParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+                let Ok(path) = params.text_document.uri.to_file_path() else {
+                    return Vec::new();
+                };
+                let mut document = Document::new(path, params.text_document.text);
+                document.refresh();
+                self.documents
+                    .insert(params.text_document.uri.clone(), document);
+                params.text_document.uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let Ok(mut params) =
+                    serde_json::from_value::<DidChangeTextDocumentParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+                // the server only supports full document sync
+                let (Some(document), Some(change)) = (
+                    self.documents.get_mut(&params.text_document.uri),
+                    params.content_changes.pop(),
+                ) else {
+                    return Vec::new();
+                };
+                document.text = change.text;
+                params.text_document.uri
+            }
+            DidSaveTextDocument::METHOD => {
+                // other documents may include or reference the saved file
+                for document in self.documents.values_mut() {
+                    document.refresh();
+                }
+                return self
+                    .documents
+                    .keys()
+                    .filter_map(|uri| self.publish_diagnostics(uri))
+                    .collect();
+            }
+            DidCloseTextDocument::METHOD => {
+                let Ok(params) =
+                    serde_json::from_value::<DidCloseTextDocumentParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+                self.documents.remove(&params.text_document.uri);
+                return vec![Notification::new(
+                    PublishDiagnostics::METHOD.to_string(),
+                    PublishDiagnosticsParams::new(params.text_document.uri, Vec::new(), None),
+                )];
+            }
+            _ => return Vec::new(),
+        };
+        self.publish_diagnostics(&uri).into_iter().collect()
+    }
+
+    fn publish_diagnostics(&self, uri: &Url) -> Option<Notification> {
+        let document = self.documents.get(uri)?;
+        Some(Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            PublishDiagnosticsParams::new(uri.clone(), document.diagnostics(), None),
+        ))
+    }
+
+    fn document(&self, uri: &Url, position: Position) -> Option<(&Document, usize)> {
+        let document = self.documents.get(uri)?;
+        let offset = analysis::position_to_offset(&document.text, position);
+        Some((document, offset))
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(request: Request) -> Result<T, serde_json::Error> {
+    serde_json::from_value(request.params)
+}